@@ -83,29 +83,27 @@
 //! ```
 
 extern crate ignore;
-extern crate walkdir;
 
 extern crate bitflags;
 #[cfg(test)]
 extern crate tempfile;
 
 use ignore::overrides::{Override, OverrideBuilder};
-use ignore::Match;
+use ignore::WalkBuilder;
 use std::cmp::Ordering;
 use std::path::Path;
 use std::path::PathBuf;
-use walkdir::WalkDir;
 
 /// Error from parsing globs.
 #[derive(Debug)]
 pub struct GlobError(ignore::Error);
 
 /// Error from iterating on files.
-pub type WalkError = walkdir::Error;
+pub type WalkError = ignore::Error;
 /// A directory entry.
 ///
 /// This is the type of value that is yielded from the iterators defined in this crate.
-pub type DirEntry = walkdir::DirEntry;
+pub type DirEntry = ignore::DirEntry;
 
 impl From<std::io::Error> for GlobError {
     fn from(e: std::io::Error) -> Self {
@@ -129,11 +127,7 @@ impl std::fmt::Display for GlobError {
     }
 }
 
-impl std::error::Error for GlobError {
-    fn description(&self) -> &str {
-        self.0.description()
-    }
-}
+impl std::error::Error for GlobError {}
 
 bitflags::bitflags! {
     /// Possible file type filters.
@@ -154,9 +148,12 @@ bitflags::bitflags! {
 pub struct GlobWalkerBuilder {
     root: PathBuf,
     patterns: Vec<String>,
-    walker: WalkDir,
+    walker: WalkBuilder,
+    min_depth: usize,
     case_insensitive: bool,
     file_type: Option<FileType>,
+    ignore_files: bool,
+    skip_hidden: bool,
 }
 
 impl GlobWalkerBuilder {
@@ -193,9 +190,12 @@ impl GlobWalkerBuilder {
         GlobWalkerBuilder {
             root: base.as_ref().into(),
             patterns: patterns.iter().map(normalize_pattern).collect::<_>(),
-            walker: WalkDir::new(base),
+            walker: WalkBuilder::new(base),
+            min_depth: 0,
             case_insensitive: false,
             file_type: None,
+            ignore_files: false,
+            skip_hidden: false,
         }
     }
 
@@ -205,7 +205,7 @@ impl GlobWalkerBuilder {
     /// to the `new` function on this type. Its direct descendents have depth
     /// `1`, and their descendents have depth `2`, and so on.
     pub fn min_depth(mut self, depth: usize) -> Self {
-        self.walker = self.walker.min_depth(depth);
+        self.min_depth = depth;
         self
     }
 
@@ -219,7 +219,7 @@ impl GlobWalkerBuilder {
     /// it will actually avoid descending into directories when the depth is
     /// exceeded.
     pub fn max_depth(mut self, depth: usize) -> Self {
-        self.walker = self.walker.max_depth(depth);
+        self.walker.max_depth(Some(depth));
         self
     }
 
@@ -235,65 +235,20 @@ impl GlobWalkerBuilder {
     ///
     /// [`DirEntry`]: struct.DirEntry.html
     pub fn follow_links(mut self, yes: bool) -> Self {
-        self.walker = self.walker.follow_links(yes);
+        self.walker.follow_links(yes);
         self
     }
 
-    /// Set the maximum number of simultaneously open file descriptors used
-    /// by the iterator.
-    ///
-    /// `n` must be greater than or equal to `1`. If `n` is `0`, then it is set
-    /// to `1` automatically. If this is not set, then it defaults to some
-    /// reasonably low number.
-    ///
-    /// This setting has no impact on the results yielded by the iterator
-    /// (even when `n` is `1`). Instead, this setting represents a trade off
-    /// between scarce resources (file descriptors) and memory. Namely, when
-    /// the maximum number of file descriptors is reached and a new directory
-    /// needs to be opened to continue iteration, then a previous directory
-    /// handle is closed and has its unyielded entries stored in memory. In
-    /// practice, this is a satisfying trade off because it scales with respect
-    /// to the *depth* of your file tree. Therefore, low values (even `1`) are
-    /// acceptable.
-    ///
-    /// Note that this value does not impact the number of system calls made by
-    /// an exhausted iterator.
-    ///
-    /// # Platform behavior
-    ///
-    /// On Windows, if `follow_links` is enabled, then this limit is not
-    /// respected. In particular, the maximum number of file descriptors opened
-    /// is proportional to the depth of the directory tree traversed.
-    pub fn max_open(mut self, n: usize) -> Self {
-        self.walker = self.walker.max_open(n);
-        self
-    }
-
-    /// Set a function for sorting directory entries.
+    /// Set a function for sorting directory entries by their path.
     ///
     /// If a compare function is set, the resulting iterator will return all
     /// paths in sorted order. The compare function will be called to compare
     /// entries from the same directory.
     pub fn sort_by<F>(mut self, cmp: F) -> Self
     where
-        F: FnMut(&DirEntry, &DirEntry) -> Ordering + Send + Sync + 'static,
+        F: Fn(&Path, &Path) -> Ordering + Send + Sync + 'static,
     {
-        self.walker = self.walker.sort_by(cmp);
-        self
-    }
-
-    /// Yield a directory's contents before the directory itself. By default,
-    /// this is disabled.
-    ///
-    /// When `yes` is `false` (as is the default), the directory is yielded
-    /// before its contents are read. This is useful when, e.g. you want to
-    /// skip processing of some directories.
-    ///
-    /// When `yes` is `true`, the iterator yields the contents of a directory
-    /// before yielding the directory itself. This is useful when, e.g. you
-    /// want to recursively delete a directory.
-    pub fn contents_first(mut self, yes: bool) -> Self {
-        self.walker = self.walker.contents_first(yes);
+        self.walker.sort_by_file_path(cmp);
         self
     }
 
@@ -314,9 +269,30 @@ impl GlobWalkerBuilder {
         self
     }
 
+    /// Toggle skipping what ignore files ignore, with the rules of the
+    /// `ignore` crate that ripgrep uses: `.ignore` files, and inside git
+    /// repositories `.gitignore` files, `.git/info/exclude` and the global
+    /// git excludes, including the ones of the parent directories. `.git`
+    /// directories are skipped as well.
+    ///
+    /// This is disabled by default.
+    pub fn ignore_files(mut self, yes: bool) -> Self {
+        self.ignore_files = yes;
+        self
+    }
+
+    /// Toggle skipping hidden files and directories, the ones whose name
+    /// starts with a `.`.
+    ///
+    /// This is disabled by default.
+    pub fn skip_hidden(mut self, yes: bool) -> Self {
+        self.skip_hidden = yes;
+        self
+    }
+
     /// Finalize and build a `GlobWalker` instance.
-    pub fn build(self) -> Result<GlobWalker, GlobError> {
-        let mut builder = OverrideBuilder::new(self.root);
+    pub fn build(mut self) -> Result<GlobWalker, GlobError> {
+        let mut builder = OverrideBuilder::new(&self.root);
 
         builder
             .case_insensitive(self.case_insensitive)
@@ -326,9 +302,35 @@ impl GlobWalkerBuilder {
             builder.add(pattern.as_ref()).map_err(GlobError)?;
         }
 
+        let ignore = builder.build().map_err(GlobError)?;
+
+        // The globs aren't handed to `WalkBuilder::overrides`, whose matches
+        // take precedence over the ignore files, so `**` would walk into
+        // every ignored directory. Directories they exclude are pruned here
+        // instead, and `next` only yields what they match.
+        let prune = ignore.clone();
+        let root = self.root.clone();
+        let skip_git = self.ignore_files;
+        self.walker
+            .standard_filters(self.ignore_files)
+            .hidden(self.skip_hidden)
+            .filter_entry(move |entry| {
+                if !entry.file_type().is_some_and(|t| t.is_dir()) {
+                    return true;
+                }
+                if skip_git && entry.file_name() == ".git" {
+                    return false;
+                }
+                match entry.path().strip_prefix(&root) {
+                    Ok(path) => !prune.matched(path, true).is_ignore(),
+                    Err(_) => true,
+                }
+            });
+
         Ok(GlobWalker {
-            ignore: builder.build().map_err(GlobError)?,
-            walker: self.walker.into_iter(),
+            ignore,
+            walker: self.walker.build(),
+            min_depth: self.min_depth,
             file_type_filter: self.file_type,
         })
     }
@@ -343,78 +345,54 @@ impl GlobWalkerBuilder {
 /// using `GlobWalker::sort_by`.
 pub struct GlobWalker {
     ignore: Override,
-    walker: walkdir::IntoIter,
+    walker: ignore::Walk,
+    min_depth: usize,
     file_type_filter: Option<FileType>,
 }
 
 impl Iterator for GlobWalker {
     type Item = Result<DirEntry, WalkError>;
 
-    // Possible optimization - Do not descend into directory that will never be a match
     fn next(&mut self) -> Option<Self::Item> {
-        let mut skip_dir = false;
+        for entry in &mut self.walker {
+            let e = match entry {
+                Ok(e) => e,
+                Err(e) => return Some(Err(e)),
+            };
 
-        // The outer loop allows us to avoid multiple mutable borrows on `self.walker` when
-        // we want to skip.
-        'skipper: loop {
-            if skip_dir {
-                self.walker.skip_current_dir();
+            if e.depth() < self.min_depth {
+                continue;
             }
 
-            // The inner loop just advances the iterator until a match is found.
-            for entry in &mut self.walker {
-                match entry {
-                    Ok(e) => {
-                        let is_dir = e.file_type().is_dir();
-
-                        let file_type = if e.file_type().is_dir() {
-                            Some(FileType::DIR)
-                        } else if e.file_type().is_file() {
-                            Some(FileType::FILE)
-                        } else if e.file_type().is_symlink() {
-                            Some(FileType::SYMLINK)
-                        } else {
-                            None
-                        };
-
-                        let file_type_matches = match (self.file_type_filter, file_type) {
-                            (None, _) => true,
-                            (Some(_), None) => false,
-                            (Some(filter), Some(actual)) => filter.contains(actual),
-                        };
-
-                        // Strip the common base directory so that the matcher will be
-                        // able to recognize the file name.
-                        // `unwrap` here is safe, since walkdir returns the files with relation
-                        // to the given base-dir.
-                        let path = e
-                            .path()
-                            .strip_prefix(self.ignore.path())
-                            .unwrap()
-                            .to_owned();
-
-                        // The path might be empty after stripping if the current base-directory is matched.
-                        if let Some("") = path.to_str() {
-                            continue 'skipper;
-                        }
-
-                        match self.ignore.matched(path, is_dir) {
-                            Match::Whitelist(_) if file_type_matches => return Some(Ok(e)),
-                            // If the directory is ignored, quit the iterator loop and
-                            // skip-out of this directory.
-                            Match::Ignore(_) if is_dir => {
-                                skip_dir = true;
-                                continue 'skipper;
-                            }
-                            _ => {}
-                        }
-                    }
-                    Err(e) => {
-                        return Some(Err(e));
-                    }
-                }
+            let is_dir = e.file_type().is_some_and(|t| t.is_dir());
+
+            let file_type = match e.file_type() {
+                Some(t) if t.is_dir() => Some(FileType::DIR),
+                Some(t) if t.is_file() => Some(FileType::FILE),
+                Some(t) if t.is_symlink() => Some(FileType::SYMLINK),
+                _ => None,
+            };
+
+            let file_type_matches = match (self.file_type_filter, file_type) {
+                (None, _) => true,
+                (Some(_), None) => false,
+                (Some(filter), Some(actual)) => filter.contains(actual),
+            };
+
+            // Strip the common base directory so that the matcher will be
+            // able to recognize the file name.
+            // `unwrap` here is safe, since the walker returns the files with relation
+            // to the given base-dir.
+            let path = e.path().strip_prefix(self.ignore.path()).unwrap();
+
+            // The path might be empty after stripping if the current base-directory is matched.
+            if path.as_os_str().is_empty() {
+                continue;
+            }
+
+            if file_type_matches && self.ignore.matched(path, is_dir).is_whitelist() {
+                return Some(Ok(e));
             }
-            break;
         }
 
         None
@@ -525,7 +503,7 @@ mod tests {
         let mut cwd = dir_path.clone();
         cwd.push("*.{png,jpg,gif}");
 
-        let glob = glob(cwd.to_str().unwrap().to_owned()).unwrap();
+        let glob = glob(cwd.to_str().unwrap()).unwrap();
         equate_to_expected(glob, expected, &dir_path);
     }
 
@@ -756,7 +734,7 @@ mod tests {
         let mut cwd = dir_path.clone();
         cwd.push("**");
         cwd.push("*.{png,jpg,gif}");
-        let glob = glob(cwd.to_str().unwrap().to_owned()).unwrap();
+        let glob = glob(cwd.to_str().unwrap()).unwrap();
         equate_to_expected(glob, expected, &dir_path);
     }
 
@@ -783,7 +761,7 @@ mod tests {
         );
 
         let glob = GlobWalkerBuilder::new(dir_path, "*")
-            .sort_by(|a, b| a.path().cmp(b.path()))
+            .sort_by(|a, b| a.cmp(b))
             .build()
             .unwrap();
         let expected = ["Pictures", "a.png", "b.png", "c.png"]
@@ -816,7 +794,7 @@ mod tests {
         );
 
         let glob = GlobWalkerBuilder::new(dir_path, "*")
-            .sort_by(|a, b| a.path().cmp(b.path()))
+            .sort_by(|a, b| a.cmp(b))
             .file_type(FileType::DIR)
             .build()
             .unwrap();
@@ -824,7 +802,7 @@ mod tests {
         equate_to_expected(glob, expected, dir_path);
 
         let glob = GlobWalkerBuilder::new(dir_path, "*")
-            .sort_by(|a, b| a.path().cmp(b.path()))
+            .sort_by(|a, b| a.cmp(b))
             .file_type(FileType::FILE)
             .build()
             .unwrap();
@@ -834,4 +812,63 @@ mod tests {
             .collect();
         equate_to_expected(glob, expected, dir_path);
     }
+
+    #[test]
+    fn test_ignore_files_and_hidden() {
+        let dir = TempDir::new().expect("Failed to create temporary folder");
+        let dir_path = dir.path();
+        for sub in [".git", "target", "src", "src/gen", ".config"] {
+            create_dir_all(dir_path.join(sub)).expect("");
+        }
+
+        touch(
+            &dir,
+            &[
+                ".git[/]HEAD",
+                "target[/]main.o",
+                "src[/]main.rs",
+                "src[/]debug.log",
+                "src[/]gen[/]keep.log",
+                "src[/]gen[/]out.rs",
+                ".config[/]app.toml",
+                ".env",
+                "notes.txt",
+            ][..],
+        );
+        std::fs::write(dir_path.join(".gitignore"), "target/\n*.log\n").expect("");
+        std::fs::write(dir_path.join("src/gen/.gitignore"), "!keep.log\n").expect("");
+        std::fs::write(dir_path.join("src/.ignore"), "out.rs\n").expect("");
+
+        let glob = GlobWalkerBuilder::new(dir_path, "**")
+            .ignore_files(true)
+            .skip_hidden(true)
+            .file_type(FileType::FILE)
+            .build()
+            .unwrap();
+        let expected = ["src[/]main.rs", "src[/]gen[/]keep.log", "notes.txt"]
+            .iter()
+            .map(normalize_path_sep)
+            .collect();
+        equate_to_expected(glob, expected, dir_path);
+
+        let glob = GlobWalkerBuilder::new(dir_path, "**")
+            .ignore_files(true)
+            .file_type(FileType::FILE)
+            .build()
+            .unwrap();
+        let expected = [
+            "src[/]main.rs",
+            "src[/]gen[/]keep.log",
+            "notes.txt",
+            ".gitignore",
+            ".config[/]app.toml",
+            ".env",
+            "src[/].ignore",
+            "src[/]gen[/].gitignore",
+        ]
+        .iter()
+        .map(normalize_path_sep)
+        .collect();
+        equate_to_expected(glob, expected, dir_path);
+    }
 }
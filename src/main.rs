@@ -1,16 +1,33 @@
 extern crate encoding_rs;
 extern crate encoding_rs_io;
 
+// vendored from globwalk, not every builder option is used by reno
+#[allow(dead_code)]
 mod glob_walk;
 mod replace;
 
-use clap::{ColorChoice, Parser, crate_version, crate_name, crate_authors, crate_description};
+use clap::error::ErrorKind;
+use clap::{
+    crate_authors, crate_description, crate_name, crate_version, ArgGroup, ColorChoice,
+    CommandFactory, Parser, Subcommand,
+};
+use replace::backup::{Backup, DEFAULT_BACKUP_SUFFIX};
+use replace::binary::{Padding, TextEncoding};
+use replace::contents::ContentOptions;
+use replace::derive::Sample;
+use replace::elf::SymbolOffset;
+use replace::hexdump::HexdumpOptions;
+use replace::out_dir::OutDir;
+use replace::patch_format::PatchFormat;
+use replace::sniff::BinaryFiles;
 use replace::*;
-use std::path::PathBuf;
+use std::io::IsTerminal;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::str;
 use std::string::String;
 
-const DEFAULT_MAX_DEPTH: &'static str = "4294967294";
+const DEFAULT_MAX_DEPTH: &str = "4294967294";
 
 #[derive(Parser)]
 #[command(name = crate_name!())]
@@ -19,11 +36,24 @@ const DEFAULT_MAX_DEPTH: &'static str = "4294967294";
 #[command(about = crate_description!(), long_about = None)]
 #[command(next_line_help = true)]
 #[command(color = ColorChoice::Auto)]
+#[command(group(ArgGroup::new("binary_mode").args(["binary", "bin_regex", "bin_text"])))]
+#[command(group(ArgGroup::new("position").args(["at", "symbol"])))]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Search regex or binary sequence if --bin is passed.
     ///
     /// In the binary mode, the search string should be a binary sequence with optional wildcards (e.g.: "\x22\x??\x??\x44\x22\x01\x69\x55" or "22 ?? ?? 44 22 01 69 55"))
-    search: String,
+    ///
+    /// D? and ?F only match one nibble, 7F&0F only the bits set in the mask.
+    /// "text" and u"text" match UTF-8 and UTF-16LE text, u32le:0xDEADBEEF, i16be:-2 or f32le:1.5 typed numbers.
+    /// Bytes and [groups] can be repeated with *4, *2..8 or ..., and [utf8:regex]*10 matches 10 bytes of text that match the regex.
+    ///
+    /// Use `reno -- patch ...` to search for the word "patch" (or "sig").
+    #[arg(required_unless_present = "search_file")]
+    search: Option<String>,
 
     /// Regex (e.g.: "Hello ${1}") in the normal mode.
     ///
@@ -31,11 +61,19 @@ struct Cli {
     ///
     /// Be sure to always run --dry before you actually replace anything.
     ///
-    /// A binary sequence (e.g.: "\x22\x01\xD5\x44\x22\x01\x69\x55") in binary mode.
+    /// A binary sequence (e.g.: "\x22\x01\xD5\x44\x22\x01\x69\x55") in binary mode. $1, $2, ... copy what the wildcards and brackets of the search matched.
     ///
     /// Dry mode if left empty.
     replace: Option<String>,
 
+    #[arg(long, requires = "binary", value_name = "FILE")]
+    /// Read the binary search from FILE instead: raw bytes, or a signature with # comments if it ends in .hex, .sig or .txt. A single positional argument is then the replacement
+    search_file: Option<PathBuf>,
+
+    #[arg(long, conflicts_with_all = ["bin_regex", "bin_text"], value_name = "FILE")]
+    /// Read the replacement from FILE instead: like --search-file in binary mode, used literally in text mode
+    replace_file: Option<PathBuf>,
+
     #[arg(long)]
     ///Don't modify files, just show what would happen.
     dry: bool,
@@ -48,12 +86,95 @@ struct Cli {
     /// Binary search and replace mode
     binary: bool,
 
+    #[arg(long, conflicts_with = "binary")]
+    /// Binary mode with a byte regex as the search, e.g. "\xDE.{2,8}\xEF". The replacement may use $1 and \xNN escapes
+    bin_regex: bool,
+
+    #[arg(long, conflicts_with_all = ["binary", "bin_regex"])]
+    /// Binary mode with plain text on both sides, e.g. "Old Name" "New". The replacement is padded to the length of the search and may not be longer
+    bin_text: bool,
+
+    #[arg(long, requires = "bin_text", value_enum, default_value_t = Padding::Nul)]
+    /// What --bin-text fills the rest of a shorter replacement with
+    pad: Padding,
+
+    #[arg(long, requires = "bin_text", value_enum, default_value_t = TextEncoding::Utf8)]
+    /// How --bin-text encodes both sides, Windows executables mostly keep their strings in UTF-16LE
+    encoding: TextEncoding,
+
+    #[arg(long, requires = "binary")]
+    /// Refuse binary replacements that are shorter or longer than the search, so offsets never shift
+    same_length: bool,
+
+    #[arg(long, requires = "binary_mode")]
+    /// Also find binary matches that start inside an earlier match. Every match is found in the original file, where matches overlap the earlier replacement wins
+    overlapping: bool,
+
+    #[arg(long, requires = "binary", default_value_t = 0, value_name = "K")]
+    /// Also accept binary matches that differ from the signature in up to K bytes that aren't wildcards
+    max_mismatches: usize,
+
+    #[arg(long, requires = "binary_mode", value_name = "N")]
+    /// Only replace the first N binary matches of each file
+    max_count: Option<usize>,
+
+    #[arg(long, requires = "binary_mode", value_name = "N")]
+    /// Leave a file unchanged and fail unless it has exactly N binary matches
+    expect_count: Option<usize>,
+
+    #[arg(long, requires = "binary_mode", value_parser = parse_range, value_name = "START..END")]
+    /// Only replace binary matches that lie completely within these offsets, e.g. 0x1000..0x8000, 0x1000.. or ..0x8000
+    range: Option<Range<usize>>,
+
+    #[arg(long, requires = "binary_mode", value_parser = parse_align, value_name = "N")]
+    /// Only replace binary matches that start at a multiple of N
+    align: Option<usize>,
+
+    #[arg(long, requires = "binary_mode", conflicts_with_all = ["range", "align"], value_parser = parse_offset, value_name = "OFFSET")]
+    /// Only try the binary search at OFFSET, and fail if it doesn't match there
+    at: Option<usize>,
+
+    #[arg(long, requires = "binary_mode", conflicts_with_all = ["range", "align"], value_parser = parse_symbol, value_name = "SYMBOL")]
+    /// Like --at, but at an ELF symbol plus an optional offset, e.g. my_func+0x10
+    symbol: Option<SymbolOffset>,
+
+    #[arg(long, requires = "position", value_name = "SIGNATURE")]
+    /// Before patching at --at or --symbol, check that the original bytes there match this signature
+    expect: Option<String>,
+
+    #[arg(
+        long = "section",
+        requires = "binary_mode",
+        conflicts_with = "position",
+        value_name = "NAME"
+    )]
+    /// Only replace binary matches inside this ELF section, e.g. .rodata. Can be given several times
+    sections: Vec<String>,
+
+    #[arg(long, requires = "binary_mode", value_name = "FORMAT")]
+    /// Write the binary replacements of every file to FILE.ips or FILE.bps instead of changing it
+    export: Option<PatchFormat>,
+
+    #[arg(long, short = 'C', default_value_t = 16, value_name = "N")]
+    /// Bytes of context shown around every binary match in the hexdump of a dry run
+    context: usize,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = BinaryFiles::Skip,
+        conflicts_with = "binary_mode",
+        value_name = "MODE"
+    )]
+    /// What text mode does with files that look binary: skip them, search them as text, or fail
+    binary_files: BinaryFiles,
+
     #[arg(long, short)]
     /// Only search and replace file contents
     contents: bool,
 
     #[arg(long, short)]
-    /// Only search and replace file names
+    /// Only search and replace file names, binary modes leave them alone without it
     names: bool,
 
     #[arg(long, short, default_value = DEFAULT_MAX_DEPTH)]
@@ -61,14 +182,147 @@ struct Cli {
     /// 0 means only current directory.
     depth: usize,
 
+    #[arg(long)]
+    /// Also walk files that .gitignore, .ignore, .git/info/exclude or the global git excludes ignore, and .git directories
+    no_ignore: bool,
+
+    #[arg(long)]
+    /// Also walk hidden files and directories, the ones whose name starts with a dot
+    hidden: bool,
+
+    #[arg(long, value_name = "SUFFIX", num_args = 0..=1, require_equals = true, default_missing_value = DEFAULT_BACKUP_SUFFIX)]
+    /// Keep a copy of every modified file, named like the original plus SUFFIX (default: ".reno-bak")
+    backup: Option<String>,
+
+    #[arg(long, value_name = "DIR")]
+    /// Put backups in DIR, mirroring the directory tree, instead of next to the originals
+    backup_dir: Option<PathBuf>,
+
+    #[arg(long, value_name = "DIR")]
+    /// Write changed files to DIR, mirroring the directory tree, and leave the originals untouched
+    out_dir: Option<PathBuf>,
+
+    #[arg(long, requires = "out_dir")]
+    /// Also copy files without any matches to --out-dir
+    copy_unmatched: bool,
+
+    #[arg(long)]
+    /// Keep the access and modification times of files whose contents are changed
+    preserve_times: bool,
+
     #[arg(long, short)]
     /// Prints (very) verbosely
     verbose: bool,
 }
 
+#[derive(Subcommand)]
+enum Command {
+    /// Apply patch files to binary files
+    #[command(subcommand)]
+    Patch(PatchCommand),
+    /// Work with binary signatures
+    #[command(subcommand)]
+    Sig(SigCommand),
+}
+
+#[derive(Subcommand)]
+enum PatchCommand {
+    /// Apply a patch to FILE: an IPS or BPS file, or a TOML patch set after checking that every patch and hash in it checks out
+    Apply {
+        /// The patch, a TOML patch set unless it ends in .ips or .bps
+        patch: PathBuf,
+
+        /// The file to patch
+        file: PathBuf,
+
+        #[arg(long, short)]
+        /// Only check and report, don't write anything
+        dry: bool,
+
+        #[arg(long, short)]
+        /// Write the patched file here instead of changing FILE
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum SigCommand {
+    /// Derive a signature from the same code in several samples, with ?? where they differ, and check how often it matches in each of them
+    Derive {
+        #[arg(required = true, value_parser = parse_sample, value_name = "FILE:OFFSET")]
+        /// Where the code starts in each sample, e.g. a.bin:0x120 b.bin:0x340
+        samples: Vec<Sample>,
+
+        #[arg(long, value_parser = parse_length, value_name = "N")]
+        /// Length of the compared windows in bytes
+        len: usize,
+    },
+}
+
 fn main() {
     let cli = Cli::parse();
 
+    match cli.command {
+        Some(Command::Patch(PatchCommand::Apply {
+            patch,
+            file,
+            dry,
+            output,
+        })) => match patch_set::apply_file(&patch, &file, dry, output.as_deref()) {
+            Ok(true) => return,
+            Ok(false) => std::process::exit(1),
+            Err(err) => {
+                println!("{}", err);
+                std::process::exit(1);
+            }
+        },
+        Some(Command::Sig(SigCommand::Derive { samples, len })) => {
+            match derive::derive_files(&samples, len) {
+                Ok(true) => return,
+                Ok(false) => std::process::exit(1),
+                Err(err) => {
+                    println!("{}", err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => {}
+    }
+
+    // with --search-file the only positional argument is the replacement
+    let (search, mut replace) = match &cli.search_file {
+        Some(_) if cli.replace.is_some() => Cli::command()
+            .error(
+                ErrorKind::TooManyValues,
+                "only the replacement can be given as an argument with --search-file",
+            )
+            .exit(),
+        Some(path) => (read_pattern(path, pattern_file::load_signature), cli.search),
+        None => (cli.search.unwrap(), cli.replace),
+    };
+    if let Some(path) = &cli.replace_file {
+        if replace.is_some() {
+            Cli::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    "the replacement can't be given both as an argument and with --replace-file",
+                )
+                .exit();
+        }
+        replace = Some(if cli.binary {
+            read_pattern(path, pattern_file::load_signature)
+        } else {
+            read_pattern(path, pattern_file::load_replacement_text)
+        });
+    }
+
+    let pattern_files = cli
+        .search_file
+        .iter()
+        .chain(&cli.replace_file)
+        .cloned()
+        .collect();
+
     let globs: Vec<String> = cli
         .globs
         .into_iter()
@@ -77,19 +331,142 @@ fn main() {
 
     let max_depth = cli.depth + 1;
 
-    walk(
+    let backup = if cli.backup.is_some() || cli.backup_dir.is_some() {
+        Some(Backup {
+            suffix: cli.backup.unwrap_or_default(),
+            dir: cli.backup_dir,
+        })
+    } else {
+        None
+    };
+
+    let options = WalkOptions {
         globs,
         max_depth,
-        cli.search.clone(),
-        cli.replace.clone(),
-        cli.names,
-        cli.contents,
-        cli.dry,
-        cli.binary,
-        cli.verbose,
-    )
-    .unwrap();
+        no_ignore: cli.no_ignore,
+        hidden: cli.hidden,
+        pattern_files,
+        names: cli.names,
+        contents: cli.contents,
+        verbose: cli.verbose,
+        content: ContentOptions {
+            dry: cli.dry,
+            binary: cli.binary,
+            bin_regex: cli.bin_regex,
+            bin_text: cli.bin_text,
+            pad: cli.pad,
+            encoding: cli.encoding,
+            backup,
+            out_dir: cli.out_dir.map(|dir| OutDir {
+                dir,
+                copy_unmatched: cli.copy_unmatched,
+                rename: None,
+            }),
+            preserve_times: cli.preserve_times,
+            same_length: cli.same_length,
+            overlapping: cli.overlapping,
+            max_mismatches: cli.max_mismatches,
+            max_count: cli.max_count,
+            expect_count: cli.expect_count,
+            range: cli.range,
+            align: cli.align,
+            at: cli.at,
+            expect: cli.expect,
+            sections: cli.sections,
+            symbol: cli.symbol,
+            regions: None,
+            base_address: 0,
+            hexdump: Some(HexdumpOptions {
+                context: cli.context,
+                color: std::io::stdout().is_terminal(),
+            }),
+            export: cli.export,
+            binary_files: cli.binary_files,
+        },
+    };
+
+    if let Err(err) = walk(search, replace, options) {
+        println!("{}", err);
+        std::process::exit(1);
+    }
+}
+
+fn read_pattern(path: &Path, load: fn(&Path) -> std::io::Result<String>) -> String {
+    load(path).unwrap_or_else(|err| {
+        println!("Could not read {}: {}", path.display(), err);
+        std::process::exit(1);
+    })
 }
 
 #[cfg(test)]
 mod test;
+
+/// Parses a file offset, in hex with `0x` or decimal.
+fn parse_offset(s: &str) -> Result<usize, String> {
+    let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.map_err(|_| format!("`{}` is not an offset like 0x1F00 or 7936", s))
+}
+
+/// Parses `START..END`, either side may be left out.
+fn parse_range(s: &str) -> Result<Range<usize>, String> {
+    let (start, end) = s
+        .split_once("..")
+        .ok_or_else(|| format!("`{}` is not a range like 0x1000..0x8000", s))?;
+    let start = if start.is_empty() {
+        0
+    } else {
+        parse_offset(start)?
+    };
+    let end = if end.is_empty() {
+        usize::MAX
+    } else {
+        parse_offset(end)?
+    };
+    if end < start {
+        return Err(format!("the range `{}` ends before it starts", s));
+    }
+    Ok(start..end)
+}
+
+/// Parses `FILE:OFFSET`, splitting at the last colon so Windows paths work.
+fn parse_sample(s: &str) -> Result<Sample, String> {
+    let (path, offset) = s
+        .rsplit_once(':')
+        .ok_or_else(|| format!("`{}` is not a sample like a.bin:0x120", s))?;
+    Ok(Sample {
+        path: PathBuf::from(path),
+        offset: parse_offset(offset)?,
+    })
+}
+
+/// Parses `NAME` or `NAME+OFFSET`.
+fn parse_symbol(s: &str) -> Result<SymbolOffset, String> {
+    let (name, offset) = match s.rsplit_once('+') {
+        Some((name, offset)) => (name, parse_offset(offset)?),
+        None => (s, 0),
+    };
+    if name.is_empty() {
+        return Err(format!("`{}` is not a symbol like my_func+0x10", s));
+    }
+    Ok(SymbolOffset {
+        name: name.to_string(),
+        offset,
+    })
+}
+
+fn parse_length(s: &str) -> Result<usize, String> {
+    match parse_offset(s)? {
+        0 => Err("the length has to be at least 1".to_string()),
+        len => Ok(len),
+    }
+}
+
+fn parse_align(s: &str) -> Result<usize, String> {
+    match parse_offset(s)? {
+        0 => Err("the alignment has to be at least 1".to_string()),
+        align => Ok(align),
+    }
+}
@@ -0,0 +1,110 @@
+use std::fs;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+
+/// Replaces the contents of `path` without ever leaving it half written.
+///
+/// The new contents go to a temporary file in the same directory, which is
+/// fsynced and then renamed over the original. Symlinks are resolved first so
+/// the link itself is kept. Permissions, ownership and extended attributes of
+/// the original are copied to the new file on a best-effort basis.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    write_atomic_with(path, |writer| writer.write_all(contents))
+}
+
+/// Like [`write_atomic`], but the new contents are streamed by `write`
+/// instead of being held in memory.
+pub fn write_atomic_with<F>(path: &Path, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut dyn Write) -> io::Result<()>,
+{
+    prepare_atomic_with(path, write)?.persist()
+}
+
+/// New contents of a file, written and synced but not yet renamed over it.
+pub struct PendingWrite {
+    temp: NamedTempFile,
+    target: PathBuf,
+}
+
+impl PendingWrite {
+    /// Renames the new contents over the file. Windows refuses that while
+    /// the file is memory mapped, so every map of it has to be dropped first.
+    pub fn persist(self) -> io::Result<()> {
+        self.temp.persist(&self.target).map_err(|err| err.error)?;
+        if let Some(dir) = self.target.parent() {
+            sync_dir(dir);
+        }
+        Ok(())
+    }
+}
+
+/// The first half of [`write_atomic_with`]: streams the new contents of
+/// `path` to a temporary file next to it, which [`PendingWrite::persist`]
+/// puts in its place.
+pub fn prepare_atomic_with<F>(path: &Path, write: F) -> io::Result<PendingWrite>
+where
+    F: FnOnce(&mut dyn Write) -> io::Result<()>,
+{
+    let target = fs::canonicalize(path)?;
+    let dir = target
+        .parent()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "file has no parent"))?;
+    let metadata = fs::metadata(&target)?;
+
+    let mut temp = tempfile::Builder::new()
+        .prefix(".reno-")
+        .suffix(".tmp")
+        .tempfile_in(dir)?;
+
+    {
+        let mut writer = BufWriter::new(temp.as_file_mut());
+        write(&mut writer)?;
+        writer.flush()?;
+    }
+    copy_metadata(&target, &temp, &metadata)?;
+    temp.as_file().sync_all()?;
+
+    Ok(PendingWrite { temp, target })
+}
+
+fn copy_metadata(original: &Path, temp: &NamedTempFile, metadata: &fs::Metadata) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        // Changing the owner needs privileges, so at least try to keep the group.
+        if std::os::unix::fs::fchown(temp.as_file(), Some(metadata.uid()), Some(metadata.gid()))
+            .is_err()
+        {
+            let _ = std::os::unix::fs::fchown(temp.as_file(), None, Some(metadata.gid()));
+        }
+
+        if let Ok(names) = xattr::list(original) {
+            for name in names {
+                if let Ok(Some(value)) = xattr::get(original, &name) {
+                    let _ = xattr::set(temp.path(), &name, &value);
+                }
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = original;
+
+    // Last, since chown clears setuid bits and read-only files refuse new xattrs.
+    temp.as_file().set_permissions(metadata.permissions())
+}
+
+/// Makes the rename durable, not supported (or needed) on every platform.
+fn sync_dir(dir: &Path) {
+    #[cfg(unix)]
+    if let Ok(dir) = fs::File::open(dir) {
+        let _ = dir.sync_all();
+    }
+
+    #[cfg(not(unix))]
+    let _ = dir;
+}
@@ -0,0 +1,63 @@
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use crate::replace::walk::{escape_glob, exclude_path_glob};
+
+pub const DEFAULT_BACKUP_SUFFIX: &str = ".reno-bak";
+
+/// Where copies of modified files are kept, like `sed -i.bak`.
+#[derive(Debug, Clone)]
+pub struct Backup {
+    /// Appended to the file name of every backup, may be empty when `dir` is set.
+    pub suffix: String,
+    /// Directory mirroring the walked tree, backups go next to the originals if `None`.
+    pub dir: Option<PathBuf>,
+}
+
+impl Backup {
+    /// Returns where the backup of `path` is stored.
+    pub fn path_for(&self, path: &Path) -> PathBuf {
+        let mut backup_path = match &self.dir {
+            Some(dir) => dir.join(relative_to_walk(path)),
+            None => path.to_path_buf(),
+        };
+
+        let mut file_name = backup_path.file_name().unwrap_or_default().to_os_string();
+        file_name.push(&self.suffix);
+        backup_path.set_file_name(file_name);
+        backup_path
+    }
+
+    /// Copies `path` to its backup location and returns the backup path.
+    pub fn create(&self, path: &Path) -> io::Result<PathBuf> {
+        let backup_path = self.path_for(path);
+        if let Some(parent) = backup_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(path, &backup_path)?;
+        Ok(backup_path)
+    }
+
+    /// Glob patterns that keep a walk from picking up its own backups.
+    pub fn ignore_globs(&self, base_dir: &Path) -> Vec<String> {
+        let mut globs = vec![];
+
+        if !self.suffix.is_empty() {
+            globs.push(format!("!*{}", escape_glob(&self.suffix)));
+        }
+
+        if let Some(dir) = &self.dir {
+            globs.extend(exclude_path_glob(dir, base_dir));
+        }
+
+        globs
+    }
+}
+
+/// Strips the leading `./`, root and `..` components so the path can be nested in a backup dir.
+fn relative_to_walk(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect()
+}
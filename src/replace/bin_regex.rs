@@ -0,0 +1,196 @@
+//! The `--bin-regex` mode: the search is a `regex::bytes` regex without
+//! Unicode, so `\xDE.{2,8}\xEF` matches raw bytes of any length.
+
+use crate::replace::binary::{BinaryMatch, BinaryPatch};
+use crate::replace::contents::DoContentError;
+use regex::bytes::{Regex, RegexBuilder};
+
+/// A byte regex and the template its matches are replaced with.
+#[derive(Debug, Clone)]
+pub struct BinaryRegex {
+    regex: Regex,
+    /// Raw bytes with `$1` / `${name}` references, expanded per match.
+    replace: Vec<u8>,
+}
+
+impl BinaryRegex {
+    /// Compiles `str_search` with `(?-u)` semantics and `.` matching every
+    /// byte, and decodes `\xNN` escapes in `str_replace`.
+    pub fn parse(str_search: &str, str_replace: &str) -> Result<BinaryRegex, DoContentError> {
+        let regex = RegexBuilder::new(str_search)
+            .unicode(false)
+            .dot_matches_new_line(true)
+            .build()
+            .map_err(|err| DoContentError::InvalidBinaryRegex(err.to_string()))?;
+        Ok(BinaryRegex {
+            regex,
+            replace: unescape_bytes(str_replace)?,
+        })
+    }
+}
+
+/// Empty matches are skipped, signatures can't be empty either. Otherwise a
+/// regex like `\xDE*` would insert its replacement at every other offset.
+impl BinaryPatch for BinaryRegex {
+    fn find_matches(&self, haystack: &[u8], overlapping: bool) -> Vec<BinaryMatch> {
+        let to_match = |found: regex::bytes::Match| BinaryMatch {
+            start: found.start(),
+            end: found.end(),
+            ends: vec![found.end()],
+            mismatches: vec![],
+        };
+        if !overlapping {
+            return self
+                .regex
+                .find_iter(haystack)
+                .filter(|found| !found.is_empty())
+                .map(to_match)
+                .collect();
+        }
+
+        // the leftmost match from every offset, so at most one per start
+        let mut matches: Vec<BinaryMatch> = vec![];
+        let mut from = 0;
+        while from <= haystack.len() {
+            match self.regex.find_at(haystack, from) {
+                Some(found) => {
+                    from = found.start() + 1;
+                    if !found.is_empty() {
+                        matches.push(to_match(found));
+                    }
+                }
+                None => break,
+            }
+        }
+        matches
+    }
+
+    fn matches_at(&self, haystack: &[u8], offset: usize) -> Option<BinaryMatch> {
+        // a match starting at `offset` is always the leftmost one from there
+        self.regex
+            .find_at(haystack, offset)
+            .filter(|found| found.start() == offset && !found.is_empty())
+            .map(|found| BinaryMatch {
+                start: found.start(),
+                end: found.end(),
+                ends: vec![found.end()],
+                mismatches: vec![],
+            })
+    }
+
+    fn replacement_for(&self, haystack: &[u8], found: &BinaryMatch) -> Vec<u8> {
+        let mut replacement = vec![];
+        // searching from the start of the match finds the same match again
+        if let Some(captures) = self.regex.captures_at(haystack, found.start) {
+            captures.expand(&self.replace, &mut replacement);
+        }
+        replacement
+    }
+}
+
+/// Turns `\xNN`, `\n`, `\r`, `\t`, `\0` and `\\` into bytes, everything else is
+/// kept as UTF-8 so capture references still work.
+fn unescape_bytes(template: &str) -> Result<Vec<u8>, DoContentError> {
+    let mut bytes = vec![];
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                let byte = u8::from_str_radix(&hex, 16)
+                    .ok()
+                    .filter(|_| hex.len() == 2)
+                    .ok_or_else(|| {
+                        DoContentError::InvalidBinaryRegex(format!(
+                            "`\\x{}` in the replacement is not a hex byte",
+                            hex
+                        ))
+                    })?;
+                bytes.push(byte);
+            }
+            Some('n') => bytes.push(b'\n'),
+            Some('r') => bytes.push(b'\r'),
+            Some('t') => bytes.push(b'\t'),
+            Some('0') => bytes.push(0),
+            Some('\\') => bytes.push(b'\\'),
+            other => {
+                return Err(DoContentError::InvalidBinaryRegex(format!(
+                    "unknown escape `\\{}` in the replacement",
+                    other.map(String::from).unwrap_or_default()
+                )))
+            }
+        }
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_variable_length_with_captures() {
+        let pattern = BinaryRegex::parse(r"\xDE(.{2,8}?)\xEF", r"\x00${1}\x00\x00").unwrap();
+        let haystack = b"\x01\xDE\x0A\x0B\x0C\xEF\x02\xDE\x0A\xEF";
+        let matches = pattern.find_matches(haystack, false);
+        assert_eq!(matches.len(), 1);
+        assert_eq!((matches[0].start, matches[0].end), (1, 6));
+
+        let mut result = vec![];
+        pattern
+            .write_patched(haystack, &matches, &mut result)
+            .unwrap();
+        assert_eq!(result, b"\x01\x00\x0A\x0B\x0C\x00\x00\x02\xDE\x0A\xEF");
+    }
+
+    #[test]
+    fn test_dot_matches_any_byte() {
+        let pattern = BinaryRegex::parse(r"A.B", "").unwrap();
+        assert_eq!(pattern.find_matches(b"A\nB A\xFFB", false).len(), 2);
+    }
+
+    #[test]
+    fn test_overlapping() {
+        let pattern = BinaryRegex::parse(r"\xAA.", "").unwrap();
+        let starts = |overlapping| {
+            pattern
+                .find_matches(b"\xAA\xAA\xAA", overlapping)
+                .iter()
+                .map(|found| found.start)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(starts(false), vec![0]);
+        assert_eq!(starts(true), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_empty_matches_are_skipped() {
+        let pattern = BinaryRegex::parse(r"\xDE*", r"\x00").unwrap();
+        let haystack = b"\x01\xDE\xDE\x02";
+        for overlapping in [false, true] {
+            let matches = pattern.find_matches(haystack, overlapping);
+            assert_eq!((matches[0].start, matches[0].end), (1, 3));
+            assert!(matches.iter().all(|found| found.end > found.start));
+        }
+        assert!(pattern.matches_at(haystack, 0).is_none());
+
+        let mut result = vec![];
+        let matches = pattern.find_matches(haystack, false);
+        pattern
+            .write_patched(haystack, &matches, &mut result)
+            .unwrap();
+        assert_eq!(result, b"\x01\x00\x02");
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(BinaryRegex::parse(r"\xDE(", "").is_err());
+        assert!(BinaryRegex::parse(r"\xDE", r"\xZZ").is_err());
+        assert!(BinaryRegex::parse(r"\xDE", r"\q").is_err());
+    }
+}
@@ -0,0 +1,814 @@
+use crate::replace::bin_regex::BinaryRegex;
+use crate::replace::contents::{ContentOptions, DoContentError};
+use crate::replace::patch_format::Edit;
+use crate::replace::signature::{
+    match_nodes, parse_replacement, parse_signature, ByteMatcher, Node, Tolerance,
+};
+use itertools::Itertools;
+use memchr::memmem;
+use std::io;
+use std::io::Write;
+use std::ops::Range;
+
+/// One piece of a binary replacement.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplaceItem {
+    Bytes(Vec<u8>),
+    /// Keep whatever the search node with this index matched, written as `??`
+    /// at the same position or as a `$1` capture anywhere.
+    Keep(usize),
+    /// Patch bits of what the fixed length search node with this index
+    /// matched, e.g. `?0` clears the low nibble.
+    Patch(usize, Vec<ByteMatcher>),
+}
+
+/// A parsed binary search signature and its replacement.
+///
+/// The replacement may be shorter or longer than the search. A `??`, or a
+/// bracket of only `??` like `[??]...`, keeps what the search element at the
+/// same position matched. Nibble wildcards and masks like `?0` or `80&80`
+/// only overwrite some bits of the byte at the same position.
+///
+/// Every search element that is not just plain bytes, like `??`, `D?` or a
+/// bracket with wildcards, is a capture numbered from 1, which the replacement
+/// can copy anywhere with `$1` or `${1}`.
+#[derive(Debug, Clone)]
+pub struct BinaryPattern {
+    pub search: Vec<Node>,
+    pub replace: Vec<ReplaceItem>,
+    anchor: Option<(usize, Vec<u8>)>,
+    /// How many bytes of a match may differ from the signature.
+    max_mismatches: usize,
+}
+
+/// Where a signature matched, and where each of its elements ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BinaryMatch {
+    pub start: usize,
+    pub end: usize,
+    pub ends: Vec<usize>,
+    /// Offsets of the bytes that differ from the signature, see
+    /// [`BinaryPattern::with_max_mismatches`].
+    pub mismatches: Vec<usize>,
+}
+
+impl BinaryMatch {
+    /// The bytes matched by the search element with index `i`.
+    pub fn segment(&self, i: usize) -> Range<usize> {
+        let start = if i == 0 { self.start } else { self.ends[i - 1] };
+        start..self.ends[i]
+    }
+}
+
+/// A way of finding and replacing matches in binary data.
+pub trait BinaryPatch: Send + Sync {
+    /// All matches from left to right. By default a match starts after the
+    /// previous one ended, with `overlapping` one may start at every offset.
+    fn find_matches(&self, haystack: &[u8], overlapping: bool) -> Vec<BinaryMatch>;
+
+    /// The match starting at `offset`, if there is one.
+    fn matches_at(&self, haystack: &[u8], offset: usize) -> Option<BinaryMatch>;
+
+    /// The bytes that replace `found`.
+    fn replacement_for(&self, haystack: &[u8], found: &BinaryMatch) -> Vec<u8>;
+
+    /// Streams `haystack` to `writer` with the replacement written over every
+    /// match in `matches`, in a single pass.
+    ///
+    /// All matches were found in the original data, so the result doesn't
+    /// depend on the order of the writes. Where matches overlap the earlier one
+    /// wins, and only the rest of the later replacement is written, which
+    /// needs it to be as long as its match (see [`check_overlaps`]).
+    fn write_patched(
+        &self,
+        haystack: &[u8],
+        matches: &[BinaryMatch],
+        writer: &mut dyn Write,
+    ) -> io::Result<()> {
+        let mut last = 0;
+        for edit in patch_edits(self, haystack, matches) {
+            writer.write_all(&haystack[last..edit.range.start])?;
+            writer.write_all(&edit.bytes)?;
+            last = edit.range.end;
+        }
+        writer.write_all(&haystack[last..])
+    }
+}
+
+/// The changes `write_patched` makes to `haystack`, in order and without
+/// overlaps: where matches overlap the earlier one wins and the later one
+/// only replaces the bytes after it.
+pub fn patch_edits<'a, P: BinaryPatch + ?Sized>(
+    pattern: &'a P,
+    haystack: &'a [u8],
+    matches: &'a [BinaryMatch],
+) -> impl Iterator<Item = Edit> + 'a {
+    let mut last = 0;
+    matches.iter().filter_map(move |found| {
+        let edit = if found.start >= last {
+            Edit {
+                range: found.start..found.end,
+                bytes: pattern.replacement_for(haystack, found),
+            }
+        } else if found.end > last {
+            let mut bytes = pattern.replacement_for(haystack, found);
+            bytes.drain(..last - found.start);
+            Edit {
+                range: last..found.end,
+                bytes,
+            }
+        } else {
+            return None;
+        };
+        last = last.max(found.end);
+        Some(edit)
+    })
+}
+
+/// Finds the matches of `pattern` that obey the offset constraints in
+/// `options`: only at `--at` (after checking `--expect` there), or inside
+/// `--range` and the `regions`, and on an `--align` boundary.
+///
+/// The constraints are addresses, `haystack` starts at `options.base_address`.
+/// The matches are offsets into `haystack`.
+pub fn select_matches(
+    pattern: &dyn BinaryPatch,
+    haystack: &[u8],
+    options: &ContentOptions,
+) -> Result<Vec<BinaryMatch>, DoContentError> {
+    let base = options.base_address;
+    if let Some(at) = options.at {
+        let offset = at
+            .checked_sub(base)
+            .filter(|offset| *offset < haystack.len())
+            .ok_or(DoContentError::NoMatchAt(at))?;
+        if let Some(expect) = &options.expect {
+            let expected = BinaryPattern::parse(expect, "", false)?;
+            if expected.matches_at(haystack, offset).is_none() {
+                return Err(DoContentError::ExpectMismatch(at));
+            }
+        }
+        return match pattern.matches_at(haystack, offset) {
+            Some(found) => Ok(vec![found]),
+            None => Err(DoContentError::NoMatchAt(at)),
+        };
+    }
+
+    if options.range.is_none() && options.align.is_none() && options.regions.is_none() {
+        return Ok(pattern.find_matches(haystack, options.overlapping));
+    }
+
+    // a match outside the constraints must not hide one inside, so filter all
+    // of them before dropping overlaps
+    let range = options.range.clone().unwrap_or(0..usize::MAX);
+    let align = options.align.unwrap_or(1);
+    let mut matches = vec![];
+    let mut last = 0;
+    for found in pattern.find_matches(haystack, true) {
+        let in_region = |region: &Range<usize>| {
+            base + found.start >= region.start && base + found.end <= region.end
+        };
+        if !in_region(&range)
+            || options
+                .regions
+                .as_ref()
+                .is_some_and(|regions| !regions.iter().any(in_region))
+            || !(base + found.start).is_multiple_of(align)
+            || (!options.overlapping && found.start < last)
+        {
+            continue;
+        }
+        last = found.end.max(found.start + 1);
+        matches.push(found);
+    }
+    Ok(matches)
+}
+
+/// Makes sure every match that overlaps an earlier one has a replacement as
+/// long as itself, otherwise there is no sensible way to write both.
+pub fn check_overlaps(
+    pattern: &dyn BinaryPatch,
+    haystack: &[u8],
+    matches: &[BinaryMatch],
+) -> Result<(), DoContentError> {
+    let mut last = 0;
+    for found in matches {
+        if found.start < last
+            && pattern.replacement_for(haystack, found).len() != found.end - found.start
+        {
+            return Err(DoContentError::OverlappingLengthMismatch(found.start));
+        }
+        last = last.max(found.end);
+    }
+    Ok(())
+}
+
+/// What `--bin-text` fills the rest of a shorter replacement with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Padding {
+    #[default]
+    Nul,
+    Space,
+}
+
+impl Padding {
+    fn char(&self) -> char {
+        match self {
+            Padding::Nul => '\0',
+            Padding::Space => ' ',
+        }
+    }
+}
+
+/// How `--bin-text` encodes both sides.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TextEncoding {
+    #[default]
+    Utf8,
+    Utf16le,
+}
+
+impl TextEncoding {
+    fn encode(&self, text: &str) -> Vec<u8> {
+        match self {
+            TextEncoding::Utf8 => text.as_bytes().to_vec(),
+            TextEncoding::Utf16le => text.encode_utf16().flat_map(u16::to_le_bytes).collect(),
+        }
+    }
+}
+
+/// Parses the search and replacement for the binary mode selected in `options`,
+/// a signature for `--bin`, a byte regex for `--bin-regex` or plain text for
+/// `--bin-text`.
+pub fn parse_patch(
+    str_search: &str,
+    str_replace: &str,
+    options: &ContentOptions,
+) -> Result<Box<dyn BinaryPatch>, DoContentError> {
+    if options.bin_regex {
+        Ok(Box::new(BinaryRegex::parse(str_search, str_replace)?))
+    } else if options.bin_text {
+        Ok(Box::new(text_pattern(
+            str_search,
+            str_replace,
+            options.pad,
+            options.encoding,
+        )?))
+    } else {
+        Ok(Box::new(
+            BinaryPattern::parse(str_search, str_replace, options.same_length)?
+                .with_max_mismatches(options.max_mismatches)?,
+        ))
+    }
+}
+
+/// A pattern that replaces the text `str_search` with `str_replace`, both in
+/// `encoding` and padded to the same length so nothing after it moves.
+pub fn text_pattern(
+    str_search: &str,
+    str_replace: &str,
+    pad: Padding,
+    encoding: TextEncoding,
+) -> Result<BinaryPattern, DoContentError> {
+    let search = encoding.encode(str_search);
+    let mut replace = encoding.encode(str_replace);
+    if replace.len() > search.len() {
+        return Err(DoContentError::TextTooLong(search.len(), replace.len()));
+    }
+    // both sides are whole code units, so the padding fills the gap exactly
+    let pad = encoding.encode(&pad.char().to_string());
+    while replace.len() < search.len() {
+        replace.extend_from_slice(&pad);
+    }
+    BinaryPattern::parse(&to_hex_string(&search), &to_hex_string(&replace), true)
+}
+
+impl BinaryPattern {
+    /// Parses both sides of a binary replacement, `same_length` rejects
+    /// replacements that would shift the offsets of the following bytes.
+    pub fn parse(
+        str_search: &str,
+        str_replace: &str,
+        same_length: bool,
+    ) -> Result<BinaryPattern, DoContentError> {
+        let search = parse_signature(str_search)?;
+
+        if search.is_empty() {
+            return Err(DoContentError::InvalidSignature(
+                "the search signature is empty".to_string(),
+            ));
+        }
+        if search.iter().map(Node::min_len).sum::<usize>() == 0 {
+            return Err(DoContentError::InvalidSignature(
+                "the search signature can match zero bytes".to_string(),
+            ));
+        }
+
+        let replace = parse_replacement(str_replace)?
+            .iter()
+            .enumerate()
+            .map(|(i, node)| to_replace_item(i, node, &search))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if same_length {
+            check_same_length(&search, &replace)?;
+        }
+
+        let anchor = find_anchor(&search);
+        Ok(BinaryPattern {
+            search,
+            replace,
+            anchor,
+            max_mismatches: 0,
+        })
+    }
+
+    /// Lets matches differ from the signature in up to `max` bytes that
+    /// aren't `??`. Any byte of the literal anchor could be one of them, so
+    /// every offset is tried instead. `max` has to leave at least one byte
+    /// that must match, otherwise any bytes at all would match.
+    pub fn with_max_mismatches(mut self, max: usize) -> Result<BinaryPattern, DoContentError> {
+        let literal_len = self.search.iter().map(Node::min_literal_len).sum();
+        if max > 0 && max >= literal_len {
+            return Err(DoContentError::TooManyMismatches(max, literal_len));
+        }
+        self.max_mismatches = max;
+        if max > 0 {
+            self.anchor = None;
+        }
+        Ok(self)
+    }
+}
+
+impl BinaryPatch for BinaryPattern {
+    fn matches_at(&self, haystack: &[u8], offset: usize) -> Option<BinaryMatch> {
+        let tolerance = Tolerance::new(self.max_mismatches);
+        let ends = match_nodes(&self.search, haystack, offset, &tolerance)?;
+        Some(BinaryMatch {
+            start: offset,
+            end: *ends.last().unwrap(),
+            ends,
+            mismatches: tolerance.into_mismatches(),
+        })
+    }
+    /// Candidates are found by looking for the longest literal run at the
+    /// start of the signature with `memmem`, and only those are checked
+    /// against the whole signature.
+    fn find_matches(&self, haystack: &[u8], overlapping: bool) -> Vec<BinaryMatch> {
+        let mut matches = vec![];
+
+        let (anchor_offset, anchor) = match &self.anchor {
+            Some(anchor) => anchor,
+            None => {
+                // nothing to anchor on, try every offset
+                let mut offset = 0;
+                while offset < haystack.len() {
+                    match self.matches_at(haystack, offset) {
+                        Some(found) => {
+                            offset = if overlapping { offset + 1 } else { found.end };
+                            matches.push(found);
+                        }
+                        None => offset += 1,
+                    }
+                }
+                return matches;
+            }
+        };
+
+        let finder = memmem::Finder::new(anchor);
+        let mut from = *anchor_offset;
+        while from < haystack.len() {
+            let hit = match finder.find(&haystack[from..]) {
+                Some(hit) => from + hit,
+                None => break,
+            };
+            match self.matches_at(haystack, hit - anchor_offset) {
+                Some(found) => {
+                    from = if overlapping {
+                        hit + 1
+                    } else {
+                        found.end + anchor_offset
+                    };
+                    matches.push(found);
+                }
+                None => from = hit + 1,
+            }
+        }
+        matches
+    }
+
+    fn replacement_for(&self, haystack: &[u8], found: &BinaryMatch) -> Vec<u8> {
+        let mut replacement = vec![];
+        for item in &self.replace {
+            match item {
+                ReplaceItem::Bytes(bytes) => replacement.extend_from_slice(bytes),
+                ReplaceItem::Keep(i) => replacement.extend_from_slice(&haystack[found.segment(*i)]),
+                ReplaceItem::Patch(i, bytes) => replacement.extend(
+                    haystack[found.segment(*i)]
+                        .iter()
+                        .zip(bytes)
+                        .map(|(original, byte)| byte.apply(*original)),
+                ),
+            }
+        }
+        replacement
+    }
+}
+
+/// Turns the replacement element with index `i` into bytes to write or a
+/// segment of the match to keep.
+fn to_replace_item(i: usize, node: &Node, search: &[Node]) -> Result<ReplaceItem, DoContentError> {
+    if let Node::Capture(capture) = node {
+        let captures: Vec<_> = (0..search.len())
+            .filter(|j| search[*j].is_capture())
+            .collect();
+        return captures
+            .get(capture - 1)
+            .map(|j| ReplaceItem::Keep(*j))
+            .ok_or_else(|| {
+                DoContentError::InvalidSignature(format!(
+                    "replacement refers to ${} but the search only has {} captures",
+                    capture,
+                    captures.len()
+                ))
+            });
+    }
+    if node.is_wildcard() {
+        if i >= search.len() {
+            return Err(DoContentError::InvalidSignature(format!(
+                "wildcard at replacement element {} has nothing to keep, the search only has {} elements",
+                i + 1,
+                search.len()
+            )));
+        }
+        return Ok(ReplaceItem::Keep(i));
+    }
+
+    let bytes = node.flatten().ok_or_else(|| {
+        DoContentError::InvalidSignature(format!(
+            "replacement element {} has to be bytes, a fixed repetition of bytes, or only ?? to keep what was matched",
+            i + 1
+        ))
+    })?;
+    if let Some(literal) = bytes.iter().map(ByteMatcher::literal).collect() {
+        return Ok(ReplaceItem::Bytes(literal));
+    }
+
+    // partial wildcards patch the bytes that were matched at the same position
+    let search_len = search.get(i).and_then(Node::fixed_len);
+    if search_len != Some(bytes.len()) {
+        return Err(DoContentError::InvalidSignature(format!(
+            "replacement element {} has wildcards, so search element {} has to match exactly {} bytes",
+            i + 1,
+            i + 1,
+            bytes.len()
+        )));
+    }
+    Ok(ReplaceItem::Patch(i, bytes))
+}
+
+/// Makes sure the replacement always has the length of the match, which
+/// requires every variable length part of the search to be kept exactly once.
+fn check_same_length(search: &[Node], replace: &[ReplaceItem]) -> Result<(), DoContentError> {
+    let kept = |i: usize| {
+        replace
+            .iter()
+            .filter(|item| **item == ReplaceItem::Keep(i))
+            .count()
+    };
+
+    let mut search_len = 0;
+    for (i, node) in search.iter().enumerate() {
+        match node.fixed_len() {
+            Some(len) => search_len += len,
+            None if kept(i) == 1 => {}
+            None => {
+                return Err(DoContentError::InvalidSignature(format!(
+                    "search element {} has a variable length, --same-length needs it to be kept exactly once with ?? or a capture in the replacement",
+                    i + 1
+                )))
+            }
+        }
+    }
+
+    let mut replace_len = 0;
+    for item in replace {
+        match item {
+            ReplaceItem::Bytes(bytes) => replace_len += bytes.len(),
+            ReplaceItem::Keep(i) => replace_len += search[*i].fixed_len().unwrap_or_default(),
+            ReplaceItem::Patch(_, bytes) => replace_len += bytes.len(),
+        }
+    }
+    // kept variable length elements are left out on both sides
+    if search_len != replace_len {
+        return Err(DoContentError::LengthMismatch(search_len, replace_len));
+    }
+    Ok(())
+}
+
+/// The longest run of literal bytes in the fixed length start of the signature
+/// and its offset, `None` if there is none.
+fn find_anchor(search: &[Node]) -> Option<(usize, Vec<u8>)> {
+    let prefix: Vec<_> = search.iter().map_while(Node::flatten).flatten().collect();
+
+    let mut longest: Option<(usize, usize)> = None;
+    let mut run_start = 0;
+    for (i, byte) in prefix.iter().enumerate() {
+        if byte.literal().is_none() {
+            run_start = i + 1;
+            continue;
+        }
+        let run_len = i + 1 - run_start;
+        if longest.is_none_or(|(_, len)| run_len > len) {
+            longest = Some((run_start, run_len));
+        }
+    }
+    longest.map(|(start, len)| {
+        let bytes = prefix[start..start + len]
+            .iter()
+            .filter_map(ByteMatcher::literal)
+            .collect();
+        (start, bytes)
+    })
+}
+
+/// Formats bytes like the signatures are written, e.g. "DE AD BE EF".
+pub fn to_hex_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02X}", byte)).join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn starts(matches: &[BinaryMatch]) -> Vec<usize> {
+        matches.iter().map(|found| found.start).collect()
+    }
+
+    fn apply(pattern: &BinaryPattern, haystack: &[u8], matches: &[BinaryMatch]) -> Vec<u8> {
+        let mut result = vec![];
+        pattern
+            .write_patched(haystack, matches, &mut result)
+            .unwrap();
+        result
+    }
+
+    #[test]
+    fn test_shorter_and_longer_replacements() {
+        let haystack = [0xDE, 0xAD, 0xBE, 0xEF, 0x01, 0xDE, 0xAD, 0xBE, 0xEF];
+
+        let shorter = BinaryPattern::parse("DE ?? BE EF", "00 ??", false).unwrap();
+        let matches = shorter.find_matches(&haystack, false);
+        assert_eq!(starts(&matches), vec![0, 5]);
+        assert_eq!(
+            apply(&shorter, &haystack, &matches),
+            vec![0x00, 0xAD, 0x01, 0x00, 0xAD]
+        );
+
+        let longer = BinaryPattern::parse("BE EF", "BE EF 00 00", false).unwrap();
+        let matches = longer.find_matches(&haystack, false);
+        assert_eq!(
+            apply(&longer, &haystack, &matches),
+            vec![0xDE, 0xAD, 0xBE, 0xEF, 0, 0, 0x01, 0xDE, 0xAD, 0xBE, 0xEF, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_find_matches_anchors_on_literals() {
+        let pattern = BinaryPattern::parse("?? AA AA ?? 01", "", false).unwrap();
+        assert_eq!(pattern.anchor, Some((1, vec![0xAA, 0xAA])));
+
+        let haystack = [
+            0xAA, 0xAA, 0xAA, 0xAA, 0x00, 0x01, 0xFF, 0xAA, 0xAA, 0x02, 0x01,
+        ];
+        assert_eq!(starts(&pattern.find_matches(&haystack, false)), vec![1, 6]);
+
+        let wildcards = BinaryPattern::parse("?? ??", "", false).unwrap();
+        assert_eq!(
+            starts(&wildcards.find_matches(&[1, 2, 3, 4, 5], false)),
+            vec![0, 2]
+        );
+    }
+
+    #[test]
+    fn test_keep_text_segment() {
+        let pattern = BinaryPattern::parse(
+            "4C 79 72 61 [utf8:[A-z0-9]{4}]*4",
+            "00 00 00 00 [??]...",
+            true,
+        )
+        .unwrap();
+        let haystack = b"xxLyraAb12yy";
+        let matches = pattern.find_matches(haystack, false);
+        assert_eq!(starts(&matches), vec![2]);
+        assert_eq!(matches[0].segment(4), 6..10);
+        assert_eq!(apply(&pattern, haystack, &matches), b"xx\0\0\0\0Ab12yy");
+
+        let variable = BinaryPattern::parse("DE ??... EF", "00 ?? 00", true).unwrap();
+        let haystack = b"\xDE\x01\x02\xEF";
+        let matches = variable.find_matches(haystack, false);
+        assert_eq!(apply(&variable, haystack, &matches), b"\x00\x01\x02\x00");
+        assert!(BinaryPattern::parse("DE ??... EF", "00 00 00", true).is_err());
+    }
+
+    #[test]
+    fn test_patch_bits() {
+        let pattern = BinaryPattern::parse("A? ?? 01", "?0 80&80 ??", true).unwrap();
+        assert_eq!(pattern.anchor, Some((2, vec![0x01])));
+        let haystack = [0xA7, 0x12, 0x01, 0xB7, 0x12, 0x01];
+        let matches = pattern.find_matches(&haystack, false);
+        assert_eq!(starts(&matches), vec![0]);
+        assert_eq!(
+            apply(&pattern, &haystack, &matches),
+            vec![0xA0, 0x92, 0x01, 0xB7, 0x12, 0x01]
+        );
+
+        assert!(matches!(
+            BinaryPattern::parse("A? ??... 01", "?0 ?0", false),
+            Err(DoContentError::InvalidSignature(_))
+        ));
+    }
+
+    #[test]
+    fn test_captures() {
+        let swap = BinaryPattern::parse("01 ?? ?? 02", "01 $2 $1 02", true).unwrap();
+        let haystack = [0x01, 0xAA, 0xBB, 0x02];
+        let matches = swap.find_matches(&haystack, false);
+        assert_eq!(
+            apply(&swap, &haystack, &matches),
+            vec![0x01, 0xBB, 0xAA, 0x02]
+        );
+
+        // the literal group is not a capture, the variable one is $2
+        let pattern =
+            BinaryPattern::parse("[?? ??] [DE AD] [??]... EF", "${2} DE AD EF $1", true).unwrap();
+        let haystack = [0x01, 0x02, 0xDE, 0xAD, 0x03, 0x04, 0xEF];
+        let matches = pattern.find_matches(&haystack, false);
+        assert_eq!(
+            apply(&pattern, &haystack, &matches),
+            vec![0x03, 0x04, 0xDE, 0xAD, 0xEF, 0x01, 0x02]
+        );
+        // lengths only add up when the variable capture is used once
+        assert!(BinaryPattern::parse("[?? ??] [DE AD] [??]... EF", "$2 $2", true).is_err());
+
+        assert!(matches!(
+            BinaryPattern::parse("?? 01", "$2", false),
+            Err(DoContentError::InvalidSignature(_))
+        ));
+        assert!(matches!(
+            BinaryPattern::parse("$1", "", false),
+            Err(DoContentError::SignatureSyntax { column: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn test_overlapping() {
+        let pattern = BinaryPattern::parse("AA AA", "BB CC", false).unwrap();
+        let haystack = [0xAA, 0xAA, 0xAA, 0x00];
+        assert_eq!(starts(&pattern.find_matches(&haystack, false)), vec![0]);
+
+        // both matches come from the original data, the first one wins
+        let matches = pattern.find_matches(&haystack, true);
+        assert_eq!(starts(&matches), vec![0, 1]);
+        assert!(check_overlaps(&pattern, &haystack, &matches).is_ok());
+        assert_eq!(
+            apply(&pattern, &haystack, &matches),
+            vec![0xBB, 0xCC, 0xCC, 0x00]
+        );
+
+        let wildcards = BinaryPattern::parse("?? ??", "", false).unwrap();
+        let matches = wildcards.find_matches(&haystack, true);
+        assert_eq!(starts(&matches), vec![0, 1, 2]);
+        assert!(matches!(
+            check_overlaps(&wildcards, &haystack, &matches),
+            Err(DoContentError::OverlappingLengthMismatch(1))
+        ));
+    }
+
+    #[test]
+    fn test_offset_constraints() {
+        let pattern = BinaryPattern::parse("AA AA", "00 00", false).unwrap();
+        let haystack = [0xAA, 0xAA, 0xAA, 0xAA, 0xAA, 0x00, 0xAA, 0xAA];
+        let select = |options: ContentOptions| {
+            select_matches(&pattern, &haystack, &options).map(|matches| starts(&matches))
+        };
+
+        // the match at 0 would hide the one at 1 without filtering first
+        let in_range = ContentOptions {
+            range: Some(1..5),
+            ..Default::default()
+        };
+        assert_eq!(select(in_range).unwrap(), vec![1, 3]);
+
+        let aligned = ContentOptions {
+            align: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(select(aligned).unwrap(), vec![0, 3, 6]);
+
+        let at = |at, expect: &str| ContentOptions {
+            at: Some(at),
+            expect: Some(expect.to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            select(at(6, "00 AA")),
+            Err(DoContentError::ExpectMismatch(6))
+        ));
+        assert_eq!(select(at(6, "AA AA")).unwrap(), vec![6]);
+        assert!(matches!(
+            select(at(5, "00")),
+            Err(DoContentError::NoMatchAt(5))
+        ));
+    }
+
+    #[test]
+    fn test_length_errors() {
+        assert!(matches!(
+            BinaryPattern::parse("DE AD", "00", true),
+            Err(DoContentError::LengthMismatch(2, 1))
+        ));
+        assert!(matches!(
+            BinaryPattern::parse("DE AD", "00 00 ??", false),
+            Err(DoContentError::InvalidSignature(_))
+        ));
+    }
+
+    #[test]
+    fn test_max_mismatches() {
+        let haystack = b"\x00\xB8\x01\x00\xC3\xB8\x07\x00\xC3\xB9\x07\x01\xC3";
+        let pattern = BinaryPattern::parse("B8 01 00 C3", "B8 00 00 C3", false).unwrap();
+        assert_eq!(starts(&pattern.find_matches(haystack, false)), vec![1]);
+
+        let fuzzy = pattern.with_max_mismatches(1).unwrap();
+        let matches = fuzzy.find_matches(haystack, false);
+        assert_eq!(starts(&matches), vec![1, 5]);
+        assert_eq!(matches[0].mismatches, Vec::<usize>::new());
+        assert_eq!(matches[1].mismatches, vec![6]);
+        assert_eq!(
+            apply(&fuzzy, haystack, &matches),
+            b"\x00\xB8\x00\x00\xC3\xB8\x00\x00\xC3\xB9\x07\x01\xC3"
+        );
+
+        // at least one byte that isn't a wildcard has to match
+        let mismatches = |search: &str, max: usize| {
+            BinaryPattern::parse(search, "", false)
+                .unwrap()
+                .with_max_mismatches(max)
+        };
+        assert!(mismatches("00 00", 1).is_ok());
+        assert!(matches!(
+            mismatches("00 00", 2),
+            Err(DoContentError::TooManyMismatches(2, 2))
+        ));
+        assert!(matches!(
+            mismatches("DE ?? D? [AA]*2..", 4),
+            Err(DoContentError::TooManyMismatches(4, 4))
+        ));
+        assert!(mismatches("\"text\"", 0).is_ok());
+    }
+
+    #[test]
+    fn test_text_pattern() {
+        let haystack = b"\x01Old Name\x00\x02";
+        let patched = |pattern: BinaryPattern| {
+            let mut result = vec![];
+            pattern
+                .write_patched(
+                    haystack,
+                    &pattern.find_matches(haystack, false),
+                    &mut result,
+                )
+                .unwrap();
+            result
+        };
+        assert_eq!(
+            patched(text_pattern("Old Name", "New", Padding::Nul, TextEncoding::Utf8).unwrap()),
+            b"\x01New\x00\x00\x00\x00\x00\x00\x02"
+        );
+        assert_eq!(
+            patched(text_pattern("Old", "\"Q\"", Padding::Space, TextEncoding::Utf8).unwrap()),
+            b"\x01\"Q\" Name\x00\x02"
+        );
+        assert!(matches!(
+            text_pattern("Old", "Newer", Padding::Nul, TextEncoding::Utf8),
+            Err(DoContentError::TextTooLong(3, 5))
+        ));
+
+        let haystack = b"\x01O\x00l\x00d\x00\x02";
+        let pattern = text_pattern("Old", "Q", Padding::Space, TextEncoding::Utf16le).unwrap();
+        let mut result = vec![];
+        pattern
+            .write_patched(
+                haystack,
+                &pattern.find_matches(haystack, false),
+                &mut result,
+            )
+            .unwrap();
+        assert_eq!(result, b"\x01Q\x00 \x00 \x00\x02");
+        assert!(matches!(
+            text_pattern("Old", "Newer", Padding::Nul, TextEncoding::Utf16le),
+            Err(DoContentError::TextTooLong(6, 10))
+        ));
+    }
+}
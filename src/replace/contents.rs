@@ -1,21 +1,28 @@
 extern crate encoding_rs;
 extern crate encoding_rs_io;
 
-use anyhow::bail;
+use crate::replace::atomic::{prepare_atomic_with, write_atomic};
+use crate::replace::backup::Backup;
+use crate::replace::binary::{
+    check_overlaps, parse_patch, patch_edits, select_matches, to_hex_string, BinaryMatch,
+    BinaryPatch, Padding, TextEncoding,
+};
+use crate::replace::elf::{self, SymbolOffset};
+use crate::replace::firmware::{FirmwareImage, ImageFormat};
+use crate::replace::hexdump::{dump_change, HexdumpOptions};
+use crate::replace::out_dir::OutDir;
+use crate::replace::patch_format::{self, apply_edits, Edit, PatchFormat, PatchFormatError};
+use crate::replace::sniff::{looks_binary, BinaryFiles, SNIFF_LEN};
 use anyhow::ensure;
 use anyhow::Result;
 use encoding_rs_io::DecodeReaderBytesBuilder;
-use itertools::Itertools;
-use regex::Regex;
-use std::borrow::BorrowMut;
+use filetime::FileTime;
+use memmap2::Mmap;
+use regex::{Captures, RegexBuilder};
 use std::fs;
 use std::fs::File;
-use std::fs::OpenOptions;
-use std::io::Seek;
-use std::io::SeekFrom;
-use std::io::Write;
-use std::io::{BufReader, Read};
-//use std::os::windows::prelude::FileExt;
+use std::io::{BufReader, Read, Seek};
+use std::ops::Range;
 use std::path::Path;
 use std::path::PathBuf;
 use std::str;
@@ -28,12 +35,86 @@ pub enum DoContentError {
     EmptyFile,
     #[error("File \"{0}\" is not in a valid encoding, try running with --bin\n")]
     ReadDecodingError(String),
-    #[error("IO Error\n")]
+    #[error("Binary file, searching it as text needs --binary-files=text\n")]
+    BinaryFile,
+    #[error("No matches found\n")]
     NoMatchesFound,
     #[error("Error writing to file: {0}\n")]
     WriteError(String, #[source] std::io::Error),
-    #[error("Could not decode string as hex: {0}\n")]
-    HexDecodeError(String, #[source] std::num::ParseIntError),
+    #[error("Error backing up file: {0}\n")]
+    BackupError(String, #[source] std::io::Error),
+    #[error("Invalid binary signature: {message}\n    {signature}\n    {}^\n", " ".repeat(*.column - 1))]
+    SignatureSyntax {
+        signature: String,
+        /// 1-based, in characters
+        column: usize,
+        message: String,
+    },
+    #[error("Invalid binary signature: {0}\n")]
+    InvalidSignature(String),
+    #[error("Expected {0} matches but found {1}, the file was not changed\n")]
+    UnexpectedMatchCount(usize, usize),
+    #[error("The match at {0:#x} overlaps an earlier one and its replacement has a different length, which --overlapping can't write\n")]
+    OverlappingLengthMismatch(usize),
+    #[error("The search does not match at {0:#x}, the file was not changed\n")]
+    NoMatchAt(usize),
+    #[error("The bytes at {0:#x} don't match --expect, the file was not changed\n")]
+    ExpectMismatch(usize),
+    #[error("Invalid binary regex: {0}\n")]
+    InvalidBinaryRegex(String),
+    #[error("The search is {0} bytes but the replacement is {1} bytes, which --same-length does not allow\n")]
+    LengthMismatch(usize, usize),
+    #[error("Could not export a patch for {0}: {1}\n")]
+    ExportError(String, #[source] PatchFormatError),
+    #[error("Not an ELF file, --section and --symbol need one\n")]
+    NotElf,
+    #[error("The ELF file has no section {0} with data in the file\n")]
+    UnknownSection(String),
+    #[error("The ELF file has no symbol {0}\n")]
+    UnknownSymbol(String),
+    #[error("The symbol {0} (plus the offset) does not point at data in the file\n")]
+    SymbolNotInFile(String),
+    #[error("Line {line} is not a valid {format} record: {message}\n")]
+    InvalidImage {
+        format: &'static str,
+        line: usize,
+        message: String,
+    },
+    #[error("The replacement at {0:#x} is not as long as its match, which would move the data after it to other addresses\n")]
+    ImageLengthChange(usize),
+    #[error("The replacement is {1} bytes but the text it replaces only {0}, --bin-text can't make it longer\n")]
+    TextTooLong(usize, usize),
+    #[error("--max-mismatches {0} would let every byte of the signature differ, it has to be less than its {1} bytes that aren't wildcards\n")]
+    TooManyMismatches(usize, usize),
+}
+
+/// Line break style of a text file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineEnding {
+    Lf,
+    CrLf,
+}
+
+impl LineEnding {
+    /// Returns the line ending used by most lines in `text`, `Lf` on ties.
+    pub fn detect(text: &str) -> LineEnding {
+        let crlf = text.matches("\r\n").count();
+        let lf = text.matches('\n').count() - crlf;
+        if crlf > lf {
+            LineEnding::CrLf
+        } else {
+            LineEnding::Lf
+        }
+    }
+
+    /// Rewrites every line break in `text` to this line ending.
+    pub fn normalize(&self, text: &str) -> String {
+        let lf = text.replace("\r\n", "\n");
+        match self {
+            LineEnding::Lf => lf,
+            LineEnding::CrLf => lf.replace('\n', "\r\n"),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -43,6 +124,10 @@ pub struct ContentReplacementInfo {
     pub length: usize,
     pub new: String,
     pub original: String,
+    /// Before and after view of a binary match in dry runs.
+    pub hexdump: Option<String>,
+    /// Offsets of the bytes of a binary match that differ from the signature.
+    pub mismatches: Vec<usize>,
 }
 
 #[derive(Debug)]
@@ -50,54 +135,153 @@ pub struct FileReplacementInfo {
     pub did_change: bool,
     pub path: PathBuf,
     pub replacements: Vec<ContentReplacementInfo>,
+    /// Why the original access and modification times could not be restored.
+    pub preserve_times_error: Option<String>,
+    /// The patch file the replacements were exported to instead.
+    pub exported: Option<PathBuf>,
 }
 
-// Instead of a Vec<u8> we will use a Vec<ByteMatcher> to allow for wildcards
-#[derive(Debug)]
-pub struct ByteMatcher {
-    pub value: u8,
-    pub is_wildcard: bool,
+/// Settings for how file contents are searched and replaced.
+#[derive(Debug, Clone, Default)]
+pub struct ContentOptions {
+    pub dry: bool,
+    pub binary: bool,
+    /// Binary mode with a byte regex instead of a signature.
+    pub bin_regex: bool,
+    /// Binary mode with plain text on both sides, padded to the same length.
+    pub bin_text: bool,
+    /// What `bin_text` pads shorter replacements with.
+    pub pad: Padding,
+    /// How `bin_text` encodes both sides.
+    pub encoding: TextEncoding,
+    pub backup: Option<Backup>,
+    pub out_dir: Option<OutDir>,
+    pub preserve_times: bool,
+    /// Refuse binary replacements that are not as long as the search.
+    pub same_length: bool,
+    /// Let binary matches start inside earlier matches.
+    pub overlapping: bool,
+    /// Bytes of a binary match that may differ from the signature.
+    pub max_mismatches: usize,
+    /// Only replace the first this many binary matches of each file.
+    pub max_count: Option<usize>,
+    /// Leave a file untouched unless it has exactly this many binary matches.
+    pub expect_count: Option<usize>,
+    /// Only binary matches that lie completely within these offsets.
+    pub range: Option<Range<usize>>,
+    /// Only binary matches that start at a multiple of this.
+    pub align: Option<usize>,
+    /// Only try the binary search at this offset, failing if it doesn't match.
+    pub at: Option<usize>,
+    /// Signature the original bytes at `at` have to match before writing.
+    pub expect: Option<String>,
+    /// Only binary matches inside one of these ELF sections.
+    pub sections: Vec<String>,
+    /// Only try the binary search at this ELF symbol, like `at`.
+    pub symbol: Option<SymbolOffset>,
+    /// Only binary matches that lie completely within one of these offsets,
+    /// resolved from `sections` for every file.
+    pub regions: Option<Vec<Range<usize>>>,
+    /// Address of the first byte of the searched data, which isn't 0 for the
+    /// blocks of a firmware image. `at`, `range`, `align` and `regions` are
+    /// addresses.
+    pub base_address: usize,
+    /// Show binary matches of dry runs as a hexdump.
+    pub hexdump: Option<HexdumpOptions>,
+    /// Write binary replacements to a patch file next to each file instead
+    /// of changing it.
+    pub export: Option<PatchFormat>,
+    /// What text mode does with files that look binary.
+    pub binary_files: BinaryFiles,
 }
 
 pub fn do_contents(
     source_path: &Path,
     str_search: &str,
     str_replace: &str,
-    b_dry: bool,
-    b_bin: bool,
+    options: &ContentOptions,
 ) -> Result<Vec<FileReplacementInfo>> {
-    let mut file = OpenOptions::new().read(true).write(true).open(source_path)?;
+    let file = File::open(source_path)?;
 
     let mut replacement_infos: Vec<FileReplacementInfo> = vec![];
 
     // Binary search and replace contents
-    if b_bin {
+    if options.binary || options.bin_regex || options.bin_text {
         replacement_infos =
-            do_contents_binary(source_path, &mut file, str_search, str_replace, b_dry)?;
+            do_contents_binary(source_path, file, str_search, str_replace, options)?;
     }
     // Plain text search and replace contents
     else {
-        replacement_infos = do_contents_plain(source_path, file, str_search, str_replace, b_dry)?;
+        replacement_infos = do_contents_plain(source_path, file, str_search, str_replace, options)?;
     }
 
     Ok(replacement_infos)
 }
 
+/// Gets ready to modify `source_path` and returns the file that should be written:
+/// the original after backing it up, or a fresh copy in the output directory.
+fn prepare_write(source_path: &Path, options: &ContentOptions) -> Result<PathBuf> {
+    if let Some(out_dir) = &options.out_dir {
+        let target = out_dir.path_for(source_path);
+        copy_to(source_path, &target).map_err(|err| {
+            DoContentError::WriteError(String::from(target.to_string_lossy()), err)
+        })?;
+        return Ok(target);
+    }
+
+    if let Some(backup) = &options.backup {
+        backup.create(source_path).map_err(|err| {
+            DoContentError::BackupError(String::from(source_path.to_string_lossy()), err)
+        })?;
+    }
+    Ok(source_path.to_path_buf())
+}
+
+/// Puts the access and modification times from `metadata` back on `target`
+/// if requested, returning why that failed.
+fn restore_times(
+    target: &Path,
+    metadata: &fs::Metadata,
+    options: &ContentOptions,
+) -> Option<String> {
+    if !options.preserve_times {
+        return None;
+    }
+    let atime = FileTime::from_last_access_time(metadata);
+    let mtime = FileTime::from_last_modification_time(metadata);
+    filetime::set_file_times(target, atime, mtime)
+        .err()
+        .map(|err| err.to_string())
+}
+
+/// Copies `source_path` to `target`, creating missing parent folders.
+pub fn copy_to(source_path: &Path, target: &Path) -> std::io::Result<()> {
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::copy(source_path, target)?;
+    Ok(())
+}
+
 fn do_contents_plain(
     source_path: &Path,
-    file: File,
+    mut file: File,
     str_search: &str,
     str_replace: &str,
-    b_dry: bool,
+    options: &ContentOptions,
 ) -> Result<Vec<FileReplacementInfo>> {
-    let re = if !str_search.is_empty() {
-        Regex::new(&str_search).unwrap()
-    } else {
-        Regex::new(".*").unwrap()
-    };
+    let metadata = file.metadata()?;
+
+    // only the start is read before deciding, binaries can be several GB
+    let mut bytes = vec![];
+    (&mut file).take(SNIFF_LEN as u64).read_to_end(&mut bytes)?;
+    if options.binary_files != BinaryFiles::Text {
+        ensure!(!looks_binary(&bytes), DoContentError::BinaryFile);
+    }
+    file.read_to_end(&mut bytes)?;
 
     // make reader that does BOM sniffing using encoding_rs
-    let mut reader = BufReader::new(DecodeReaderBytesBuilder::new().build(file));
+    let mut reader = DecodeReaderBytesBuilder::new().build(&bytes[..]);
 
     // read file to string
     let mut str_contents = String::new();
@@ -107,152 +291,339 @@ fn do_contents_plain(
             source_path.to_string_lossy(),
         ))))?;
 
-    // search and make sure we have matches
+    ensure!(!str_contents.is_empty(), DoContentError::EmptyFile);
 
-    let search_result = re.find_iter(&str_contents);
+    // newlines in the replacement follow the file, and `$`/`.` treat "\r\n" as one line break
+    let line_ending = LineEnding::detect(&str_contents);
+    let str_replace = line_ending.normalize(str_replace);
 
-    let v_search_result = search_result.collect::<Vec<regex::Match>>();
+    let re = RegexBuilder::new(if !str_search.is_empty() {
+        str_search
+    } else {
+        ".*"
+    })
+    .crlf(line_ending == LineEnding::CrLf)
+    .build()?;
 
-    if v_search_result.len() == 0 {
-        return Ok(vec![]);
-    }
+    // search and make sure we have matches
+
+    let v_search_result = re.captures_iter(&str_contents).collect::<Vec<Captures>>();
+
+    ensure!(!v_search_result.is_empty(), DoContentError::NoMatchesFound);
 
     // do replacement and write result
 
-    let result = re.replace_all(&str_contents, str_replace);
+    let result = re.replace_all(&str_contents, str_replace.as_str());
+
+    let mut preserve_times_error = None;
 
-    if !b_dry {
-        let write_result = fs::write(source_path, result.as_bytes());
+    if !options.dry {
+        let target = prepare_write(source_path, options)?;
+        let write_result = write_atomic(&target, result.as_bytes());
         ensure!(
             write_result.is_ok(),
             DoContentError::WriteError(String::from(result), write_result.unwrap_err())
         );
+        preserve_times_error = restore_times(&target, &metadata, options);
     }
 
-    let mut file_replacement_infos: Vec<FileReplacementInfo> = vec![];
-
-    // log changes, this will make us do re.replace_all twice, but it's fine for now.
-    for search_match in v_search_result {
-        file_replacement_infos.push(FileReplacementInfo {
-            path: source_path.to_path_buf(),
-            did_change: !b_dry,
-            replacements: vec![ContentReplacementInfo {
+    // log changes
+    let replacements = v_search_result
+        .iter()
+        .map(|captures| {
+            let search_match = captures.get(0).unwrap();
+            let mut new = String::new();
+            captures.expand(&str_replace, &mut new);
+            ContentReplacementInfo {
                 start: search_match.start(),
                 end: search_match.end(),
-                length: search_match.end() - search_match.start(),
-                new: re
-                    .replace_all(&search_match.as_str(), str_replace)
-                    .to_string(),
+                length: search_match.len(),
+                new,
                 original: search_match.as_str().to_string(),
-            }],
-        });
-    }
-    return Ok(file_replacement_infos);
+                hexdump: None,
+                mismatches: vec![],
+            }
+        })
+        .collect();
+
+    Ok(vec![FileReplacementInfo {
+        path: source_path.to_path_buf(),
+        did_change: !options.dry,
+        replacements,
+        preserve_times_error,
+        exported: None,
+    }])
 }
 
 fn do_contents_binary(
     source_path: &Path,
-    file: &mut File,
+    file: File,
     str_search: &str,
     str_replace: &str,
-    b_dry: bool,
+    options: &ContentOptions,
 ) -> Result<Vec<FileReplacementInfo>> {
-    // decode string hex signature
-    let decode_hex_bytes = |s: &str| -> Result<Vec<ByteMatcher>, DoContentError> {
-        let split_str = if s.contains("\\x") { "\\x" } else { " " };
-        return s
-            .split(split_str)
-            .filter(|s| !s.is_empty())
-            .map(|s| -> Result<ByteMatcher, DoContentError> {
-                return u8::from_str_radix(s, 16)
-                    .map(|x| ByteMatcher {
-                        value: x,
-                        is_wildcard: false,
-                    })
-                    .or_else(|err| {
-                        if s == "??" {
-                            return Ok(ByteMatcher {
-                                value: 0,
-                                is_wildcard: true,
-                            });
-                        }
-                        Err(DoContentError::HexDecodeError(
-                            String::from(str_search),
-                            err,
-                        ))
-                    });
-            })
-            .try_collect();
-    };
+    let pattern = parse_patch(str_search, str_replace, options)?;
+
+    let mut head = [0; 64];
+    let head_len = (&file).read(&mut head)?;
+    (&file).rewind()?;
+    if let Some(format) = ImageFormat::detect(source_path, &head[..head_len]) {
+        return do_contents_image(source_path, file, format, pattern.as_ref(), options);
+    }
 
-    let search_hex_bytes: Vec<ByteMatcher> = decode_hex_bytes(str_search)?;
-    let replace_hex_bytes: Vec<ByteMatcher> = decode_hex_bytes(str_replace)?;
+    let metadata = file.metadata()?;
 
-    // read file contents as binary and do replacement
-    let mut reader = BufReader::new(file.try_clone()?);
-    let mut i: usize = 0;
+    ensure!(metadata.len() > 0, DoContentError::EmptyFile);
 
-    let mut file_replacement_infos: Vec<FileReplacementInfo> = vec![];
+    // map the file instead of reading it, images can be several GB
+    // SAFETY: the mapping is only read, and writes go to a new file that is renamed over this
+    // one after the mapping is dropped. Another process truncating the file while it is mapped
+    // still makes reading the missing pages fail with SIGBUS.
+    let contents = unsafe { Mmap::map(&file)? };
 
-    let replace_length = replace_hex_bytes.len();
-    let mut potential_match: Vec<u8> = vec![0u8; search_hex_bytes.len()];
+    let options = &elf::resolve(&contents, options)?;
+    let mut matches = select_matches(pattern.as_ref(), &contents, options)?;
+    limit_matches(&mut matches, options)?;
+    if !options.dry {
+        check_overlaps(pattern.as_ref(), &contents, &matches)?;
+    }
 
-    //println!("search_hex_bytes: {:?}", search_hex_bytes);
-    //println!("replace_hex_bytes: {:?}", replace_hex_bytes);
+    let replacements = matches
+        .iter()
+        .map(|found| binary_replacement_info(pattern.as_ref(), &contents, 0, found, options))
+        .collect();
+
+    let mut preserve_times_error = None;
+    let mut exported = None;
+
+    if let Some(format) = options.export.filter(|_| !options.dry) {
+        let edits: Vec<Edit> = patch_edits(pattern.as_ref(), &contents, &matches).collect();
+        exported = Some(export_patch(
+            source_path,
+            format,
+            &contents,
+            &edits,
+            options,
+        )?);
+    } else if !options.dry {
+        let target = prepare_write(source_path, options)?;
+        let write_error =
+            |err| DoContentError::WriteError(String::from(target.to_string_lossy()), err);
+        let pending = prepare_atomic_with(&target, |writer| {
+            pattern.write_patched(&contents, &matches, writer)
+        })
+        .map_err(write_error)?;
+        // Windows can't replace a file that is still mapped or open
+        drop(contents);
+        drop(file);
+        pending.persist().map_err(write_error)?;
+        preserve_times_error = restore_times(&target, &metadata, options);
+    }
 
-    loop {
-        match reader.read_exact(&mut potential_match) {
-            Ok(_) => {}
-            Err(e) => match e.kind() {
-                std::io::ErrorKind::UnexpectedEof => break,
-                _ => {
-                    panic!("Error reading file: {:?}", e);
-                }
-            },
-        };
+    Ok(vec![FileReplacementInfo {
+        path: source_path.to_path_buf(),
+        did_change: !options.dry && exported.is_none(),
+        replacements,
+        preserve_times_error,
+        exported,
+    }])
+}
 
-        //println!("buffer: {:?}", potential_match);
-        let mut matched = true;
-        for (search_i, search_byte) in search_hex_bytes.iter().enumerate() {
-            if (search_byte.value != potential_match[search_i] && !search_byte.is_wildcard) {
-                matched = false;
-                //println!("i: {} {} != {}", i, search_byte.value, potential_match[search_i]);
-            }
+/// Binary mode for Intel HEX and S-record files: the signature is applied to
+/// the blocks of data in the file, by address, and only the changed records
+/// are rewritten.
+fn do_contents_image(
+    source_path: &Path,
+    file: File,
+    format: ImageFormat,
+    pattern: &dyn BinaryPatch,
+    options: &ContentOptions,
+) -> Result<Vec<FileReplacementInfo>> {
+    let metadata = file.metadata()?;
+
+    let mut text = String::new();
+    BufReader::new(file)
+        .read_to_string(&mut text)
+        .or(Err(DoContentError::ReadDecodingError(String::from(
+            source_path.to_string_lossy(),
+        ))))?;
+
+    ensure!(!text.is_empty(), DoContentError::EmptyFile);
+
+    // --section and --symbol only make sense for ELF files
+    let options = &elf::resolve(text.as_bytes(), options)?;
+    let image = FirmwareImage::parse(format, &text)?;
+
+    // matches of every block, which is searched on its own
+    let mut matches: Vec<(usize, BinaryMatch)> = vec![];
+    for (i, segment) in image.segments.iter().enumerate() {
+        let contains_at = |at| at >= segment.address && at < segment.address + segment.data.len();
+        if options.at.is_some_and(|at| !contains_at(at)) {
+            continue;
         }
+        let segment_options = ContentOptions {
+            base_address: segment.address,
+            ..options.clone()
+        };
+        let found = select_matches(pattern, &segment.data, &segment_options)?;
+        matches.extend(found.into_iter().map(|found| (i, found)));
+    }
+    if let Some(at) = options.at {
+        ensure!(!matches.is_empty(), DoContentError::NoMatchAt(at));
+    }
+    limit_matches(&mut matches, options)?;
 
-        if (matched) { 
-            for (matched_i, matched_byte) in potential_match.iter_mut().enumerate() {
-                if !replace_hex_bytes[matched_i].is_wildcard {
-                    *matched_byte = replace_hex_bytes[matched_i].value;
-                }
+    for (i, found) in &matches {
+        let segment = &image.segments[*i];
+        ensure!(
+            pattern.replacement_for(&segment.data, found).len() == found.end - found.start,
+            DoContentError::ImageLengthChange(segment.address + found.start)
+        );
+    }
+
+    let mut preserve_times_error = None;
+    let mut exported = None;
+
+    if !options.dry {
+        let mut patched = image.segments.clone();
+        for (i, segment) in patched.iter_mut().enumerate() {
+            let segment_matches: Vec<BinaryMatch> = matches
+                .iter()
+                .filter(|(j, _)| *j == i)
+                .map(|(_, found)| found.clone())
+                .collect();
+            if segment_matches.is_empty() {
+                continue;
             }
-        
+            let mut data = Vec::with_capacity(segment.data.len());
+            pattern
+                .write_patched(&image.segments[i].data, &segment_matches, &mut data)
+                .expect("writing to memory can't fail");
+            segment.data = data;
         }
-
-        if !b_dry && matched {
-            file.seek(SeekFrom::Start(i as u64)).unwrap_or_else(|e| panic!("Could not seek {}: {:?}", source_path.to_string_lossy(), e));
-            file.write_all(&potential_match).unwrap_or_else(|e| panic!("Could not write to {}: {:?}", source_path.to_string_lossy(), e));
-            // TODO Could use seek_write here if windows
-            //file.seek_write(&potential_match, i as u64).unwrap_or_else(|e| panic!("Could not seek_write: {:?}", e));
+        let edits = image.edits(&text, &patched);
+
+        if let Some(format) = options.export {
+            exported = Some(export_patch(
+                source_path,
+                format,
+                text.as_bytes(),
+                &edits,
+                options,
+            )?);
+        } else {
+            let target = prepare_write(source_path, options)?;
+            write_atomic(&target, &apply_edits(text.as_bytes(), &edits)).map_err(|err| {
+                DoContentError::WriteError(String::from(target.to_string_lossy()), err)
+            })?;
+            preserve_times_error = restore_times(&target, &metadata, options);
         }
+    }
 
-        if (matched) {
-            file_replacement_infos.push(FileReplacementInfo {
-                path: source_path.to_path_buf(),
-                did_change: !b_dry,
-                replacements: vec![ContentReplacementInfo {
-                    start: i,
-                    end: i + replace_length,
-                    length: replace_length,
-                    new: str_replace.to_string(),
-                    original: str_search.to_string(),
-                }],
-            });
+    let replacements = matches
+        .iter()
+        .map(|(i, found)| {
+            let segment = &image.segments[*i];
+            binary_replacement_info(pattern, &segment.data, segment.address, found, options)
+        })
+        .collect();
+
+    Ok(vec![FileReplacementInfo {
+        path: source_path.to_path_buf(),
+        did_change: !options.dry && exported.is_none(),
+        replacements,
+        preserve_times_error,
+        exported,
+    }])
+}
+
+/// Applies `--expect-count` and `--max-count` to the matches of a file.
+fn limit_matches<T>(matches: &mut Vec<T>, options: &ContentOptions) -> Result<(), DoContentError> {
+    if let Some(expected) = options.expect_count {
+        if matches.len() != expected {
+            return Err(DoContentError::UnexpectedMatchCount(
+                expected,
+                matches.len(),
+            ));
         }
+    }
+    if matches.is_empty() {
+        return Err(DoContentError::NoMatchesFound);
+    }
+    if let Some(max_count) = options.max_count {
+        matches.truncate(max_count);
+    }
+    Ok(())
+}
 
-        reader.seek_relative(-(potential_match.len() as i64) + 1)?;
-        i += 1;
+/// The report of a binary match in `haystack`, which starts at `base`.
+fn binary_replacement_info(
+    pattern: &dyn BinaryPatch,
+    haystack: &[u8],
+    base: usize,
+    found: &BinaryMatch,
+    options: &ContentOptions,
+) -> ContentReplacementInfo {
+    let matched = &haystack[found.start..found.end];
+    let replacement = pattern.replacement_for(haystack, found);
+    let hexdump = options.hexdump.filter(|_| options.dry).map(|hexdump| {
+        dump_change(
+            haystack,
+            base,
+            found.start..found.end,
+            &replacement,
+            &hexdump,
+        )
+    });
+    ContentReplacementInfo {
+        start: base + found.start,
+        end: base + found.end,
+        length: matched.len(),
+        new: to_hex_string(&replacement),
+        original: to_hex_string(matched),
+        hexdump,
+        mismatches: found
+            .mismatches
+            .iter()
+            .map(|offset| base + offset)
+            .collect(),
     }
+}
+
+/// Writes the `edits` of `source` as a patch next to `source_path` and
+/// returns where.
+fn export_patch(
+    source_path: &Path,
+    format: PatchFormat,
+    source: &[u8],
+    edits: &[Edit],
+    options: &ContentOptions,
+) -> Result<PathBuf, DoContentError> {
+    let patch = patch_format::encode(format, source, edits).map_err(|err| {
+        DoContentError::ExportError(String::from(source_path.to_string_lossy()), err)
+    })?;
+    let target = export_path(source_path, format, options);
+    write_patch_file(&target, &patch)
+        .map_err(|err| DoContentError::WriteError(String::from(target.to_string_lossy()), err))?;
+    Ok(target)
+}
 
-    return Ok(file_replacement_infos);
+/// Where the patch for `source_path` goes: next to it, or at the same place
+/// in the output directory, with the format's extension appended.
+fn export_path(source_path: &Path, format: PatchFormat, options: &ContentOptions) -> PathBuf {
+    let path = match &options.out_dir {
+        Some(out_dir) => out_dir.path_for(source_path),
+        None => source_path.to_path_buf(),
+    };
+    let mut name = path.clone().into_os_string();
+    name.push(".");
+    name.push(format.extension());
+    PathBuf::from(name)
+}
+
+fn write_patch_file(target: &Path, patch: &[u8]) -> std::io::Result<()> {
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(target, patch)
 }
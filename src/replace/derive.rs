@@ -0,0 +1,129 @@
+//! `reno sig derive`: a wildcard signature from the same code in several
+//! samples, with `??` wherever the samples differ.
+
+use crate::replace::binary::{BinaryPatch, BinaryPattern};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Offsets past this many matches are left out of the report.
+const SHOWN_OFFSETS: usize = 8;
+
+/// A window of a sample file, `path:offset` on the command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sample {
+    pub path: PathBuf,
+    pub offset: usize,
+}
+
+#[derive(Error, Debug)]
+pub enum DeriveError {
+    #[error("Could not read {0}: {1}")]
+    Read(String, #[source] io::Error),
+    #[error("{0} is only {1:#x} bytes long, the window at {2:#x} doesn't fit")]
+    OutOfBounds(String, usize, usize),
+}
+
+/// The signature matching every window: bytes the windows agree on are kept,
+/// the others become `??`. All windows have to be as long as the first one.
+pub fn derive_signature(windows: &[&[u8]]) -> String {
+    let first = windows[0];
+    (0..first.len())
+        .map(|i| {
+            if windows.iter().all(|window| window[i] == first[i]) {
+                format!("{:02X}", first[i])
+            } else {
+                "??".to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Offsets of every match of `signature` in `data`, overlapping ones included.
+pub fn match_offsets(signature: &str, data: &[u8]) -> Vec<usize> {
+    let pattern =
+        BinaryPattern::parse(signature, "", false).expect("derived signatures always parse");
+    pattern
+        .find_matches(data, true)
+        .iter()
+        .map(|found| found.start)
+        .collect()
+}
+
+/// Derives the signature of the `len` bytes at every sample, prints it, and
+/// how often it matches in each sample file. Returns whether the signature is
+/// unique in all of them.
+pub fn derive_files(samples: &[Sample], len: usize) -> Result<bool, DeriveError> {
+    let mut files = vec![];
+    for sample in samples {
+        let name = sample.path.display().to_string();
+        let data = fs::read(&sample.path).map_err(|err| DeriveError::Read(name.clone(), err))?;
+        if sample.offset.saturating_add(len) > data.len() {
+            return Err(DeriveError::OutOfBounds(name, data.len(), sample.offset));
+        }
+        files.push(data);
+    }
+
+    let windows: Vec<&[u8]> = samples
+        .iter()
+        .zip(&files)
+        .map(|(sample, data)| &data[sample.offset..sample.offset + len])
+        .collect();
+    let signature = derive_signature(&windows);
+    println!("{}", signature);
+
+    let mut unique = true;
+    for (sample, data) in samples.iter().zip(&files) {
+        let offsets = match_offsets(&signature, data);
+        unique &= offsets.len() == 1;
+        let mut shown = offsets
+            .iter()
+            .take(SHOWN_OFFSETS)
+            .map(|offset| format!("{:#x}", offset))
+            .collect::<Vec<_>>()
+            .join(", ");
+        if offsets.len() > SHOWN_OFFSETS {
+            shown.push_str(", ...");
+        }
+        println!(
+            "    {:?}: {} {} at {}{}",
+            sample.path,
+            offsets.len(),
+            if offsets.len() == 1 {
+                "match"
+            } else {
+                "matches"
+            },
+            shown,
+            if offsets.len() == 1 {
+                ""
+            } else {
+                ", not unique"
+            }
+        );
+    }
+    Ok(unique)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_derive_signature() {
+        let a = b"\xDE\xAD\x01\xEF\x10";
+        let b = b"\xDE\xAD\x02\xEF\x20";
+        let c = b"\xDE\xAD\x03\xEF\x20";
+        assert_eq!(derive_signature(&[a, b, c]), "DE AD ?? EF ??");
+        assert_eq!(derive_signature(&[a]), "DE AD 01 EF 10");
+    }
+
+    #[test]
+    fn test_match_offsets() {
+        let data = b"\xDE\x01\xEF\x00\xDE\x02\xEF\xDE\xDE\xEF";
+        assert_eq!(match_offsets("DE ?? EF", data), vec![0, 4, 7]);
+        assert_eq!(match_offsets("DE 02 EF", data), vec![4]);
+    }
+}
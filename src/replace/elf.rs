@@ -0,0 +1,186 @@
+//! ELF sections and symbols for `--section` and `--symbol`, translated to the
+//! file offsets the binary mode works with.
+
+use crate::replace::contents::{ContentOptions, DoContentError};
+use object::{Object, ObjectSection, ObjectSymbol, SectionIndex};
+use std::ops::Range;
+
+/// A symbol plus an offset into it, `my_func+0x10` on the command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SymbolOffset {
+    pub name: String,
+    pub offset: usize,
+}
+
+/// Resolves `--section` and `--symbol` for the ELF file in `data`: sections
+/// become `regions`, a symbol becomes an `at` offset. Options without either
+/// are returned as they are, so other files don't have to be ELF.
+pub fn resolve(data: &[u8], options: &ContentOptions) -> Result<ContentOptions, DoContentError> {
+    if options.sections.is_empty() && options.symbol.is_none() {
+        return Ok(options.clone());
+    }
+    let file = object::File::parse(data).map_err(|_| DoContentError::NotElf)?;
+    let mut resolved = options.clone();
+
+    if !options.sections.is_empty() {
+        let regions = options
+            .sections
+            .iter()
+            .map(|name| section_range(&file, name))
+            .collect::<Result<Vec<_>, _>>()?;
+        resolved.regions = Some(regions);
+    }
+
+    if let Some(symbol) = &options.symbol {
+        resolved.at = Some(symbol_offset(&file, symbol)?);
+    }
+    Ok(resolved)
+}
+
+fn section_range(file: &object::File, name: &str) -> Result<Range<usize>, DoContentError> {
+    let section = file
+        .section_by_name(name)
+        .ok_or_else(|| DoContentError::UnknownSection(name.to_string()))?;
+    // sections like .bss take up memory but no space in the file
+    let (start, size) = section
+        .file_range()
+        .ok_or_else(|| DoContentError::UnknownSection(name.to_string()))?;
+    Ok(start as usize..(start + size) as usize)
+}
+
+/// Symbol addresses are virtual, so the offset is found through the section
+/// the symbol is defined in. That works for shared objects, executables and
+/// relocatable files alike.
+fn symbol_offset(file: &object::File, symbol: &SymbolOffset) -> Result<usize, DoContentError> {
+    let found = file
+        .symbols()
+        .chain(file.dynamic_symbols())
+        .find(|candidate| candidate.name() == Ok(symbol.name.as_str()) && candidate.is_definition())
+        .ok_or_else(|| DoContentError::UnknownSymbol(symbol.name.clone()))?;
+
+    let not_in_file = || DoContentError::SymbolNotInFile(symbol.name.clone());
+    let index: SectionIndex = found.section_index().ok_or_else(not_in_file)?;
+    let section = file.section_by_index(index).map_err(|_| not_in_file())?;
+    let (start, size) = section.file_range().ok_or_else(not_in_file)?;
+
+    let offset = found
+        .address()
+        .checked_sub(section.address())
+        .map(|offset| offset as usize + symbol.offset)
+        .filter(|offset| *offset < size as usize)
+        .ok_or_else(not_in_file)?;
+    Ok(start as usize + offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A minimal little endian ELF64 file: .text at 0x40 ("\x55\x48\x89\xE5",
+    /// address 0x1000), .rodata at 0x44 ("hi\0"), and a symbol table with
+    /// `main` at 0x1001.
+    fn elf() -> Vec<u8> {
+        let mut data = vec![0; 0x40];
+        data[..4].copy_from_slice(b"\x7FELF");
+        data[4] = 2; // 64 bit
+        data[5] = 1; // little endian
+        data[6] = 1; // version
+        data[0x10..0x12].copy_from_slice(&3u16.to_le_bytes()); // shared object
+        data[0x12..0x14].copy_from_slice(&62u16.to_le_bytes()); // x86-64
+        data[0x14..0x18].copy_from_slice(&1u32.to_le_bytes());
+        data[0x34..0x36].copy_from_slice(&0x40u16.to_le_bytes()); // header size
+        data[0x3A..0x3C].copy_from_slice(&0x40u16.to_le_bytes()); // section header size
+        data[0x3C..0x3E].copy_from_slice(&6u16.to_le_bytes()); // section count
+        data[0x3E..0x40].copy_from_slice(&5u16.to_le_bytes()); // names section
+
+        data.extend_from_slice(b"\x55\x48\x89\xE5"); // .text at 0x40
+        data.extend_from_slice(b"hi\0\0"); // .rodata at 0x44
+        let symtab = data.len(); // 0x48
+        data.extend_from_slice(&[0; 24]); // the null symbol
+        data.extend_from_slice(&1u32.to_le_bytes()); // name "main"
+        data.push(0x12); // global function
+        data.push(0);
+        data.extend_from_slice(&1u16.to_le_bytes()); // in .text
+        data.extend_from_slice(&0x1001u64.to_le_bytes());
+        data.extend_from_slice(&2u64.to_le_bytes());
+        let strtab = data.len();
+        data.extend_from_slice(b"\0main\0");
+        let shstrtab = data.len();
+        let names = b"\0.text\0.rodata\0.symtab\0.strtab\0.shstrtab\0";
+        data.extend_from_slice(names);
+        data.resize(data.len().next_multiple_of(8), 0);
+        let section_headers = data.len();
+        data[0x28..0x30].copy_from_slice(&(section_headers as u64).to_le_bytes());
+
+        // name, type, flags, address, offset, size, link, entry size
+        let sections: [[u64; 8]; 6] = [
+            [0, 0, 0, 0, 0, 0, 0, 0],
+            [1, 1, 6, 0x1000, 0x40, 4, 0, 0],
+            [7, 1, 2, 0x2000, 0x44, 3, 0, 0],
+            [15, 2, 0, 0, symtab as u64, 48, 4, 24],
+            [23, 3, 0, 0, strtab as u64, 6, 0, 0],
+            [31, 3, 0, 0, shstrtab as u64, names.len() as u64, 0, 0],
+        ];
+        for [name, kind, flags, address, offset, size, link, entry_size] in sections {
+            data.extend_from_slice(&(name as u32).to_le_bytes());
+            data.extend_from_slice(&(kind as u32).to_le_bytes());
+            data.extend_from_slice(&flags.to_le_bytes());
+            data.extend_from_slice(&address.to_le_bytes());
+            data.extend_from_slice(&offset.to_le_bytes());
+            data.extend_from_slice(&size.to_le_bytes());
+            data.extend_from_slice(&(link as u32).to_le_bytes());
+            // the symbol table's info is the index of its first global symbol
+            data.extend_from_slice(&u32::from(kind == 2).to_le_bytes());
+            data.extend_from_slice(&1u64.to_le_bytes());
+            data.extend_from_slice(&entry_size.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn test_sections() {
+        let options = ContentOptions {
+            sections: vec![".rodata".to_string(), ".text".to_string()],
+            ..Default::default()
+        };
+        let resolved = resolve(&elf(), &options).unwrap();
+        assert_eq!(resolved.regions, Some(vec![0x44..0x47, 0x40..0x44]));
+
+        let missing = ContentOptions {
+            sections: vec![".data".to_string()],
+            ..Default::default()
+        };
+        assert!(matches!(
+            resolve(&elf(), &missing),
+            Err(DoContentError::UnknownSection(_))
+        ));
+        assert!(matches!(
+            resolve(b"not an elf file", &options),
+            Err(DoContentError::NotElf)
+        ));
+    }
+
+    #[test]
+    fn test_symbols() {
+        let at = |name: &str, offset| {
+            let options = ContentOptions {
+                symbol: Some(SymbolOffset {
+                    name: name.to_string(),
+                    offset,
+                }),
+                ..Default::default()
+            };
+            resolve(&elf(), &options).map(|resolved| resolved.at)
+        };
+        assert_eq!(at("main", 0).unwrap(), Some(0x41));
+        assert_eq!(at("main", 2).unwrap(), Some(0x43));
+        assert!(matches!(
+            at("main", 3),
+            Err(DoContentError::SymbolNotInFile(_))
+        ));
+        assert!(matches!(
+            at("missing", 0),
+            Err(DoContentError::UnknownSymbol(_))
+        ));
+    }
+}
@@ -0,0 +1,379 @@
+//! Intel HEX and Motorola S-record files, loaded into the blocks of bytes
+//! they describe so binary signatures can be applied by address.
+//!
+//! Only the data records whose bytes changed are written again, with a new
+//! checksum, every other line is kept exactly as it was.
+
+use crate::replace::contents::DoContentError;
+use crate::replace::patch_format::Edit;
+use std::fmt::Write;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    IntelHex,
+    SRecord,
+}
+
+impl ImageFormat {
+    /// The format of a firmware file, judging by its extension and `head`,
+    /// the start of the file, which has to begin with a record. Other files
+    /// with these extensions, like a signature saved as `.hex`, are not
+    /// firmware files.
+    pub fn detect(path: &Path, head: &[u8]) -> Option<ImageFormat> {
+        let format = ImageFormat::from_path(path)?;
+        let head = head.trim_ascii_start();
+        let starts_with_record = match format {
+            ImageFormat::IntelHex => head.starts_with(b":"),
+            ImageFormat::SRecord => {
+                head.first() == Some(&b'S') && head.get(1).is_some_and(u8::is_ascii_digit)
+            }
+        };
+        starts_with_record.then_some(format)
+    }
+
+    fn from_path(path: &Path) -> Option<ImageFormat> {
+        let extension = path.extension()?.to_string_lossy().to_ascii_lowercase();
+        match extension.as_str() {
+            "hex" | "ihex" | "ihx" => Some(ImageFormat::IntelHex),
+            "srec" | "s19" | "s28" | "s37" | "mot" | "sx" => Some(ImageFormat::SRecord),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            ImageFormat::IntelHex => "Intel HEX",
+            ImageFormat::SRecord => "S-record",
+        }
+    }
+}
+
+/// Consecutive bytes starting at `address`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub address: usize,
+    pub data: Vec<u8>,
+}
+
+impl Segment {
+    fn end(&self) -> usize {
+        self.address + self.data.len()
+    }
+}
+
+/// A data record and where it is in the file.
+#[derive(Debug, Clone)]
+struct DataRecord {
+    /// The whole line, without the line break.
+    text: std::ops::Range<usize>,
+    /// What goes between the start code and the data: the byte count and
+    /// address, plus the record type for Intel HEX.
+    header: Vec<u8>,
+    address: usize,
+    len: usize,
+}
+
+/// A parsed firmware file.
+#[derive(Debug, Clone)]
+pub struct FirmwareImage {
+    pub format: ImageFormat,
+    /// The data of all records, consecutive records merged.
+    pub segments: Vec<Segment>,
+    records: Vec<DataRecord>,
+}
+
+impl FirmwareImage {
+    pub fn parse(format: ImageFormat, text: &str) -> Result<FirmwareImage, DoContentError> {
+        let mut records = vec![];
+        let mut data = vec![];
+        let mut line_start = 0;
+        // Intel HEX addresses are relative to the last extended address record
+        let mut base = 0;
+
+        for (i, line) in text.split_inclusive('\n').enumerate() {
+            let start = line_start;
+            line_start += line.len();
+            let line = line.trim_end_matches(['\r', '\n']);
+            if line.trim().is_empty() {
+                continue;
+            }
+            let invalid = |message: &str| DoContentError::InvalidImage {
+                format: format.name(),
+                line: i + 1,
+                message: message.to_string(),
+            };
+
+            let parsed = match format {
+                ImageFormat::IntelHex => parse_intel_hex(line, &mut base),
+                ImageFormat::SRecord => parse_srecord(line),
+            }
+            .map_err(invalid)?;
+            if let Some((header, address, bytes)) = parsed {
+                records.push(DataRecord {
+                    text: start..start + line.len(),
+                    header,
+                    address,
+                    len: bytes.len(),
+                });
+                data.push((address, bytes, i + 1));
+            }
+        }
+
+        data.sort_by_key(|(address, ..)| *address);
+        let mut segments: Vec<Segment> = vec![];
+        for (address, bytes, line) in data {
+            match segments.last_mut() {
+                Some(last) if last.end() > address => {
+                    return Err(DoContentError::InvalidImage {
+                        format: format.name(),
+                        line,
+                        message: format!("another record already has data for {:#x}", address),
+                    })
+                }
+                Some(last) if last.end() == address => last.data.extend(bytes),
+                _ => segments.push(Segment {
+                    address,
+                    data: bytes,
+                }),
+            }
+        }
+
+        Ok(FirmwareImage {
+            format,
+            segments,
+            records,
+        })
+    }
+
+    /// The changes to the file `text` that give it the data of `patched`,
+    /// which has to have the same layout as `segments`. Only records whose
+    /// bytes changed are rewritten.
+    pub fn edits(&self, text: &str, patched: &[Segment]) -> Vec<Edit> {
+        let mut edits = vec![];
+        for record in &self.records {
+            let index = patched
+                .iter()
+                .position(|segment| {
+                    segment.address <= record.address && record.address < segment.end()
+                })
+                .expect("every record is part of a segment");
+            let offset = record.address - patched[index].address;
+            let range = offset..offset + record.len;
+            let bytes = &patched[index].data[range.clone()];
+            if self.segments[index].data[range] == *bytes {
+                continue;
+            }
+
+            let line = &text[record.text.clone()];
+            edits.push(Edit {
+                range: record.text.clone(),
+                bytes: self.render(line, &record.header, bytes).into_bytes(),
+            });
+        }
+        edits.sort_by_key(|edit| edit.range.start);
+        edits
+    }
+
+    /// A data record with `header` and `bytes` and a fresh checksum, in the
+    /// letter case of the `original` line.
+    fn render(&self, original: &str, header: &[u8], bytes: &[u8]) -> String {
+        let start_code = match self.format {
+            ImageFormat::IntelHex => ":",
+            ImageFormat::SRecord => &original[..2],
+        };
+        let sum = header
+            .iter()
+            .chain(bytes)
+            .fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        let checksum = match self.format {
+            ImageFormat::IntelHex => 0u8.wrapping_sub(sum),
+            ImageFormat::SRecord => !sum,
+        };
+
+        let lowercase = original[start_code.len()..]
+            .chars()
+            .any(|c| c.is_ascii_lowercase());
+        let mut line = start_code.to_string();
+        for byte in header.iter().chain(bytes).chain([checksum].iter()) {
+            if lowercase {
+                write!(line, "{:02x}", byte).unwrap();
+            } else {
+                write!(line, "{:02X}", byte).unwrap();
+            }
+        }
+        line
+    }
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>, &'static str> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return Err("it is not made of hex byte pairs");
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| "it has a non-hex digit"))
+        .collect()
+}
+
+type DataLine = Option<(Vec<u8>, usize, Vec<u8>)>;
+
+/// The header, address and data of a data record, `None` for other records.
+fn parse_intel_hex(line: &str, base: &mut usize) -> Result<DataLine, &'static str> {
+    let bytes = decode_hex(line.strip_prefix(':').ok_or("it doesn't start with `:`")?)?;
+    if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+        return Err("its length doesn't match its byte count");
+    }
+    if bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+        return Err("the checksum is wrong");
+    }
+    let offset = u16::from_be_bytes([bytes[1], bytes[2]]) as usize;
+    let data = &bytes[4..bytes.len() - 1];
+    let number = || data.iter().fold(0, |n, byte| n << 8 | *byte as usize);
+    match bytes[3] {
+        0x00 => Ok(Some((bytes[..4].to_vec(), *base + offset, data.to_vec()))),
+        0x02 if data.len() == 2 => {
+            *base = number() << 4;
+            Ok(None)
+        }
+        0x04 if data.len() == 2 => {
+            *base = number() << 16;
+            Ok(None)
+        }
+        0x01 | 0x03 | 0x05 => Ok(None),
+        _ => Err("it has an unknown record type"),
+    }
+}
+
+/// The header, address and data of an S1, S2 or S3 record, `None` for other
+/// records.
+fn parse_srecord(line: &str) -> Result<DataLine, &'static str> {
+    let kind = line
+        .strip_prefix('S')
+        .and_then(|rest| rest.chars().next())
+        .ok_or("it doesn't start with `S` and a record type")?;
+    let address_len = match kind {
+        '0' | '1' | '5' | '9' => 2,
+        '2' | '6' | '8' => 3,
+        '3' | '7' => 4,
+        _ => return Err("it has an unknown record type"),
+    };
+    let bytes = decode_hex(&line[2..])?;
+    if bytes.len() < 2 + address_len || bytes.len() != bytes[0] as usize + 1 {
+        return Err("its length doesn't match its byte count");
+    }
+    let (checked, checksum) = bytes.split_at(bytes.len() - 1);
+    if !checked
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+        != checksum[0]
+    {
+        return Err("the checksum is wrong");
+    }
+    if !matches!(kind, '1' | '2' | '3') {
+        return Ok(None);
+    }
+    let header = &checked[..1 + address_len];
+    let address = header[1..]
+        .iter()
+        .fold(0, |n, byte| n << 8 | *byte as usize);
+    Ok(Some((
+        header.to_vec(),
+        address,
+        checked[1 + address_len..].to_vec(),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replace::patch_format::apply_edits;
+
+    fn patch(image: &FirmwareImage, text: &str, address: usize, bytes: &[u8]) -> String {
+        let mut patched = image.segments.clone();
+        let segment = patched
+            .iter_mut()
+            .find(|segment| segment.address <= address && address < segment.end())
+            .unwrap();
+        let offset = address - segment.address;
+        segment.data[offset..offset + bytes.len()].copy_from_slice(bytes);
+        let edits = image.edits(text, &patched);
+        String::from_utf8(apply_edits(text.as_bytes(), &edits)).unwrap()
+    }
+
+    #[test]
+    fn test_intel_hex() {
+        let text = ":020000040800F2\r\n\
+                    :0400000001020304F2\r\n\
+                    :04000400deadbeefc0\r\n\
+                    :00000001FF\r\n";
+        let image = FirmwareImage::parse(ImageFormat::IntelHex, text).unwrap();
+        assert_eq!(
+            image.segments,
+            vec![Segment {
+                address: 0x0800_0000,
+                data: b"\x01\x02\x03\x04\xDE\xAD\xBE\xEF".to_vec(),
+            }]
+        );
+
+        // the record spans two lines, both are rewritten with lowercase kept
+        let patched = patch(&image, text, 0x0800_0003, b"\xFF\x00");
+        assert_eq!(
+            patched,
+            ":020000040800F2\r\n\
+             :04000000010203FFF7\r\n\
+             :0400040000adbeef9e\r\n\
+             :00000001FF\r\n"
+        );
+        FirmwareImage::parse(ImageFormat::IntelHex, &patched).unwrap();
+    }
+
+    #[test]
+    fn test_srecord() {
+        let text = "S00600004844521B\n\
+                    S1070100DEADBEEFBF\n\
+                    S1050104AABB90\n\
+                    S9030000FC\n";
+        let image = FirmwareImage::parse(ImageFormat::SRecord, text).unwrap();
+        assert_eq!(image.segments[0].address, 0x100);
+        assert_eq!(image.segments[0].data, b"\xDE\xAD\xBE\xEF\xAA\xBB");
+
+        let patched = patch(&image, text, 0x105, b"\xCC");
+        assert_eq!(
+            patched,
+            "S00600004844521B\n\
+             S1070100DEADBEEFBF\n\
+             S1050104AACC7F\n\
+             S9030000FC\n"
+        );
+        FirmwareImage::parse(ImageFormat::SRecord, &patched).unwrap();
+    }
+
+    #[test]
+    fn test_detect() {
+        let detect = |name: &str, head: &[u8]| ImageFormat::detect(Path::new(name), head);
+        assert_eq!(
+            detect("app.HEX", b"\r\n:020000040800F2"),
+            Some(ImageFormat::IntelHex)
+        );
+        assert_eq!(
+            detect("app.s19", b"S00600004844521B"),
+            Some(ImageFormat::SRecord)
+        );
+        // a signature or other data that happens to be saved as .hex
+        assert_eq!(detect("sig.hex", b"DE AD ?? EF\n"), None);
+        assert_eq!(detect("notes.mot", b"Some text"), None);
+        assert_eq!(detect("app.bin", b":020000040800F2"), None);
+    }
+
+    #[test]
+    fn test_errors() {
+        let line = |text: &str| match FirmwareImage::parse(ImageFormat::IntelHex, text) {
+            Err(DoContentError::InvalidImage { line, .. }) => line,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(line(":00000001FF\n:0400000001020304F3\n"), 2);
+        assert_eq!(line("\n:04000000010203F2\n"), 2);
+        assert!(FirmwareImage::parse(ImageFormat::SRecord, "S1050104AABB91\n").is_err());
+    }
+}
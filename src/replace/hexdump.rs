@@ -0,0 +1,142 @@
+use std::fmt::Write;
+use std::ops::Range;
+
+const ROW: usize = 16;
+
+/// How binary matches are shown in dry runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HexdumpOptions {
+    /// Bytes shown before and after every match.
+    pub context: usize,
+    /// Highlight changed bytes with ANSI colors, otherwise they are marked with
+    /// `^^` on the line below.
+    pub color: bool,
+}
+
+/// An `xxd`-style dump of the bytes around `range` before and after they are
+/// replaced with `replacement`, changed bytes highlighted.
+///
+/// The after view keeps the offsets of the original file, the bytes following
+/// a longer or shorter replacement are shown at the offsets they end up at.
+/// Rows are labeled with `base` plus the offset into `haystack`.
+pub fn dump_change(
+    haystack: &[u8],
+    base: usize,
+    range: Range<usize>,
+    replacement: &[u8],
+    options: &HexdumpOptions,
+) -> String {
+    let from = range.start.saturating_sub(options.context);
+    let to = (range.end + options.context).min(haystack.len());
+    let original = &haystack[range.clone()];
+    let changed = |i: usize| original.get(i) != replacement.get(i);
+
+    let before: Vec<_> = (from..to)
+        .map(|offset| {
+            let in_match = range.contains(&offset) && changed(offset - range.start);
+            (haystack[offset], in_match)
+        })
+        .collect();
+
+    let after: Vec<_> = haystack[from..range.start]
+        .iter()
+        .map(|byte| (*byte, false))
+        .chain(
+            replacement
+                .iter()
+                .enumerate()
+                .map(|(i, byte)| (*byte, changed(i))),
+        )
+        .chain(haystack[range.end..to].iter().map(|byte| (*byte, false)))
+        .collect();
+
+    let mut dump = String::new();
+    writeln!(dump, "before:").unwrap();
+    write_rows(
+        &mut dump,
+        base + from,
+        &before,
+        options.color.then_some("31"),
+    );
+    writeln!(dump, "after:").unwrap();
+    write_rows(
+        &mut dump,
+        base + from,
+        &after,
+        options.color.then_some("32"),
+    );
+    dump
+}
+
+/// Writes `bytes`, which start at `offset`, in rows of 16 aligned like `xxd`.
+fn write_rows(dump: &mut String, offset: usize, bytes: &[(u8, bool)], color: Option<&str>) {
+    let row_start = offset - offset % ROW;
+    let lead = offset - row_start;
+    let end = offset + bytes.len();
+
+    for row in (row_start..end).step_by(ROW) {
+        let mut hex = String::new();
+        let mut ascii = String::new();
+        let mut marks = String::new();
+        for i in 0..ROW {
+            let position = row + i;
+            if i > 0 && i % 2 == 0 {
+                hex.push(' ');
+                marks.push(' ');
+            }
+            if position < offset || position >= end {
+                hex.push_str("  ");
+                marks.push_str("  ");
+                ascii.push(' ');
+                continue;
+            }
+            let (byte, changed) = bytes[position - row_start - lead];
+            let text = if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            };
+            match (changed, color) {
+                (true, Some(color)) => {
+                    write!(hex, "\x1b[{}m{:02x}\x1b[0m", color, byte).unwrap();
+                    write!(ascii, "\x1b[{}m{}\x1b[0m", color, text).unwrap();
+                }
+                _ => {
+                    write!(hex, "{:02x}", byte).unwrap();
+                    ascii.push(text);
+                }
+            }
+            marks.push_str(if changed { "^^" } else { "  " });
+        }
+        writeln!(dump, "  {:08x}: {}  {}", row, hex, ascii.trim_end()).unwrap();
+        if color.is_none() && marks.contains('^') {
+            writeln!(dump, "            {}", marks.trim_end()).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dump_change() {
+        let haystack = b"0123456789abcdef\xDE\xAD\xBE\xEF tail";
+        let options = HexdumpOptions {
+            context: 4,
+            color: false,
+        };
+        let dump = dump_change(haystack, 0, 16..20, b"\xDE\x00\x00\xEF\x90", &options);
+        let expected = [
+            "before:",
+            "  00000000:                               6364 6566              cdef",
+            "  00000010: dead beef 2074 6169                      .... tai",
+            "              ^^ ^^",
+            "after:",
+            "  00000000:                               6364 6566              cdef",
+            "  00000010: de00 00ef 9020 7461 69                   ..... tai",
+            "              ^^ ^^   ^^",
+        ];
+        assert_eq!(dump.lines().collect::<Vec<_>>(), expected);
+    }
+}
@@ -1,7 +1,19 @@
+pub mod atomic;
+pub mod backup;
+pub mod bin_regex;
+pub mod binary;
 pub mod contents;
+pub mod derive;
+pub mod elf;
+pub mod firmware;
+pub mod hexdump;
 pub mod names;
+pub mod out_dir;
+pub mod patch_format;
+pub mod patch_set;
+pub mod pattern_file;
+pub mod signature;
+pub mod sniff;
 pub mod walk;
 
-pub use contents::*;
-pub use names::*;
 pub use walk::*;
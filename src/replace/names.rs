@@ -35,17 +35,16 @@ pub fn do_names(
     str_replace: &str,
     b_dry: bool,
 ) -> Result<Vec<NameReplacementInfo>> {
-    let re = Regex::new(&str_search)?;
+    let re = Regex::new(str_search)?;
 
     let old_path = source_path;
     let old_name = old_path
         .file_name()
         .context(DoNamesError::InvalidFilename(Box::from(old_path)))?
-        .clone()
         .to_str()
         .context(DoNamesError::InvalidFilename(Box::from(old_path)))?;
 
-    let new_name = re.replace_all(&old_name, str_replace);
+    let new_name = re.replace_all(old_name, str_replace);
     let new_path = old_path.with_file_name(PathBuf::from(new_name.clone().into_owned()));
 
     let mut name_replacements_info = vec![];
@@ -54,17 +53,9 @@ pub fn do_names(
         let mut replacement_was_ok = false;
 
         if !b_dry {
-            fs::rename(&old_path, &new_path)
-                .or_else(|err| {
-                    Err(DoNamesError::RenameError(
-                        new_name.clone().into_owned(),
-                        err,
-                    ))
-                })
-                .and_then(|_| {
-                    replacement_was_ok = true;
-                    Ok(())
-                })?;
+            fs::rename(old_path, &new_path)
+                .map_err(|err| DoNamesError::RenameError(new_name.clone().into_owned(), err))?;
+            replacement_was_ok = true;
         }
 
         name_replacements_info.push(NameReplacementInfo {
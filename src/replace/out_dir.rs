@@ -0,0 +1,42 @@
+use regex::Regex;
+use std::path::{Component, Path, PathBuf};
+
+/// Output tree that receives transformed files instead of editing them in place.
+#[derive(Debug, Clone)]
+pub struct OutDir {
+    pub dir: PathBuf,
+    /// Also copy files that neither contents nor names matched.
+    pub copy_unmatched: bool,
+    /// Name rule applied to every path component, when names are being replaced.
+    pub rename: Option<(Regex, String)>,
+}
+
+impl OutDir {
+    /// Returns where `source_path` ends up in the output tree, with renamed
+    /// files and folders already applied.
+    pub fn path_for(&self, source_path: &Path) -> PathBuf {
+        let mut path = self.dir.clone();
+        for component in source_path.components() {
+            if let Component::Normal(name) = component {
+                match (&self.rename, name.to_str()) {
+                    (Some((re, replace)), Some(name)) => {
+                        path.push(re.replace_all(name, replace.as_str()).as_ref())
+                    }
+                    _ => path.push(name),
+                }
+            }
+        }
+        path
+    }
+
+    /// Whether `source_path` gets a different name in the output tree.
+    pub fn is_renamed(&self, source_path: &Path) -> bool {
+        let mut mirrored = self.dir.clone();
+        mirrored.extend(
+            source_path
+                .components()
+                .filter(|component| matches!(component, Component::Normal(_))),
+        );
+        self.path_for(source_path) != mirrored
+    }
+}
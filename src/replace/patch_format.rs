@@ -0,0 +1,457 @@
+//! Standard binary patch formats, so changes can be handed out without the
+//! original file: IPS (offsets up to 16 MiB) and BPS.
+
+use std::ops::Range;
+use thiserror::Error;
+
+/// Largest offset an IPS record can address.
+const IPS_MAX_OFFSET: usize = 0xFF_FFFF;
+/// An IPS record at this offset would read as the "EOF" marker.
+const IPS_EOF: usize = 0x45_4F46;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum PatchFormat {
+    Ips,
+    Bps,
+}
+
+impl PatchFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            PatchFormat::Ips => "ips",
+            PatchFormat::Bps => "bps",
+        }
+    }
+
+    /// The format a patch file is in, judging by its extension.
+    pub fn from_extension(extension: &str) -> Option<PatchFormat> {
+        match extension.to_ascii_lowercase().as_str() {
+            "ips" => Some(PatchFormat::Ips),
+            "bps" => Some(PatchFormat::Bps),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum PatchFormatError {
+    #[error("IPS can't change bytes past 16 MiB (offset {0:#x}), use BPS instead")]
+    TooLarge(usize),
+    #[error("Not a valid {0} patch: {1}")]
+    Invalid(&'static str, &'static str),
+    #[error("The {0} checksum of the BPS patch doesn't match, is this the right file?")]
+    Checksum(&'static str),
+}
+
+/// One replacement: the source bytes in `range` become `bytes`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edit {
+    pub range: Range<usize>,
+    pub bytes: Vec<u8>,
+}
+
+/// Encodes the sorted, non-overlapping `edits` of `source` as a patch.
+pub fn encode(
+    format: PatchFormat,
+    source: &[u8],
+    edits: &[Edit],
+) -> Result<Vec<u8>, PatchFormatError> {
+    match format {
+        PatchFormat::Ips => encode_ips(source, &apply_edits(source, edits)),
+        PatchFormat::Bps => Ok(encode_bps(source, edits)),
+    }
+}
+
+/// Applies a patch in `format` to `source`.
+pub fn apply(
+    format: PatchFormat,
+    source: &[u8],
+    patch: &[u8],
+) -> Result<Vec<u8>, PatchFormatError> {
+    match format {
+        PatchFormat::Ips => apply_ips(source, patch),
+        PatchFormat::Bps => apply_bps(source, patch),
+    }
+}
+
+/// `source` with the sorted, non-overlapping `edits` applied.
+pub fn apply_edits(source: &[u8], edits: &[Edit]) -> Vec<u8> {
+    let mut target = Vec::with_capacity(source.len());
+    let mut last = 0;
+    for edit in edits {
+        target.extend_from_slice(&source[last..edit.range.start]);
+        target.extend_from_slice(&edit.bytes);
+        last = edit.range.end;
+    }
+    target.extend_from_slice(&source[last..]);
+    target
+}
+
+/// IPS only overwrites bytes, so the patch is the difference between `source`
+/// and `target`, plus the truncation extension if the target is shorter.
+fn encode_ips(source: &[u8], target: &[u8]) -> Result<Vec<u8>, PatchFormatError> {
+    let mut patch = b"PATCH".to_vec();
+
+    let mut offset = 0;
+    while offset < target.len() {
+        if source.get(offset) == Some(&target[offset]) {
+            offset += 1;
+            continue;
+        }
+        // a record can't start at the offset that spells "EOF"
+        let start = if offset == IPS_EOF {
+            offset - 1
+        } else {
+            offset
+        };
+        let mut end = offset;
+        while end < target.len() && end - start < 0xFFFF && source.get(end) != Some(&target[end]) {
+            end += 1;
+        }
+        if start > IPS_MAX_OFFSET {
+            return Err(PatchFormatError::TooLarge(start));
+        }
+        patch.extend_from_slice(&(start as u32).to_be_bytes()[1..]);
+        patch.extend_from_slice(&((end - start) as u16).to_be_bytes());
+        patch.extend_from_slice(&target[start..end]);
+        offset = end;
+    }
+
+    patch.extend_from_slice(b"EOF");
+    if target.len() < source.len() {
+        if target.len() > IPS_MAX_OFFSET {
+            return Err(PatchFormatError::TooLarge(target.len()));
+        }
+        patch.extend_from_slice(&(target.len() as u32).to_be_bytes()[1..]);
+    }
+    Ok(patch)
+}
+
+fn apply_ips(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchFormatError> {
+    let invalid = |reason| PatchFormatError::Invalid("IPS", reason);
+    let mut reader = patch
+        .strip_prefix(b"PATCH")
+        .ok_or_else(|| invalid("missing the PATCH header"))?;
+    let mut take = |len: usize| -> Result<&[u8], PatchFormatError> {
+        if reader.len() < len {
+            return Err(invalid("it ends in the middle of a record"));
+        }
+        let (taken, rest) = reader.split_at(len);
+        reader = rest;
+        Ok(taken)
+    };
+    let number = |bytes: &[u8]| bytes.iter().fold(0, |n, byte| n << 8 | *byte as usize);
+
+    let mut target = source.to_vec();
+    loop {
+        let offset = take(3)?;
+        if offset == b"EOF" {
+            break;
+        }
+        let offset = number(offset);
+        let size = number(take(2)?);
+        let (len, bytes) = if size == 0 {
+            // run length encoded record
+            let len = number(take(2)?);
+            (len, vec![take(1)?[0]; len])
+        } else {
+            (size, take(size)?.to_vec())
+        };
+        if target.len() < offset + len {
+            target.resize(offset + len, 0);
+        }
+        target[offset..offset + len].copy_from_slice(&bytes);
+    }
+    if let Ok(truncate) = take(3) {
+        target.truncate(number(truncate));
+    }
+    Ok(target)
+}
+
+const BPS_SOURCE_READ: usize = 0;
+const BPS_TARGET_READ: usize = 1;
+const BPS_SOURCE_COPY: usize = 2;
+const BPS_TARGET_COPY: usize = 3;
+
+fn write_varint(patch: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            patch.push(0x80 | byte);
+            return;
+        }
+        patch.push(byte);
+        value -= 1;
+    }
+}
+
+/// Unchanged bytes are read from the source where they stay at the same
+/// offset and copied from it where earlier edits shifted them, replacements
+/// are stored in the patch.
+fn encode_bps(source: &[u8], edits: &[Edit]) -> Vec<u8> {
+    let target = apply_edits(source, edits);
+    let mut patch = b"BPS1".to_vec();
+    write_varint(&mut patch, source.len());
+    write_varint(&mut patch, target.len());
+    write_varint(&mut patch, 0);
+
+    let mut output_offset = 0;
+    let mut source_relative_offset: usize = 0;
+    let mut copy_source = |patch: &mut Vec<u8>, range: Range<usize>, output_offset: usize| {
+        if range.is_empty() {
+            return;
+        }
+        if range.start == output_offset {
+            write_varint(patch, (range.len() - 1) << 2 | BPS_SOURCE_READ);
+            return;
+        }
+        write_varint(patch, (range.len() - 1) << 2 | BPS_SOURCE_COPY);
+        let (distance, negative) = match range.start.checked_sub(source_relative_offset) {
+            Some(distance) => (distance, false),
+            None => (source_relative_offset - range.start, true),
+        };
+        write_varint(patch, distance << 1 | negative as usize);
+        source_relative_offset = range.end;
+    };
+
+    let mut last = 0;
+    for edit in edits {
+        copy_source(&mut patch, last..edit.range.start, output_offset);
+        output_offset += edit.range.start - last;
+        if !edit.bytes.is_empty() {
+            write_varint(&mut patch, (edit.bytes.len() - 1) << 2 | BPS_TARGET_READ);
+            patch.extend_from_slice(&edit.bytes);
+            output_offset += edit.bytes.len();
+        }
+        last = edit.range.end;
+    }
+    copy_source(&mut patch, last..source.len(), output_offset);
+
+    patch.extend_from_slice(&crc32fast::hash(source).to_le_bytes());
+    patch.extend_from_slice(&crc32fast::hash(&target).to_le_bytes());
+    let patch_crc = crc32fast::hash(&patch);
+    patch.extend_from_slice(&patch_crc.to_le_bytes());
+    patch
+}
+
+fn apply_bps(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchFormatError> {
+    let invalid = |reason| PatchFormatError::Invalid("BPS", reason);
+    if patch.len() < 4 + 12 || !patch.starts_with(b"BPS1") {
+        return Err(invalid("missing the BPS1 header"));
+    }
+    let (body, checksums) = patch.split_at(patch.len() - 12);
+    let checksum = |i: usize| u32::from_le_bytes(checksums[i * 4..i * 4 + 4].try_into().unwrap());
+    if crc32fast::hash(&patch[..patch.len() - 4]) != checksum(2) {
+        return Err(PatchFormatError::Checksum("patch"));
+    }
+    if crc32fast::hash(source) != checksum(0) {
+        return Err(PatchFormatError::Checksum("source"));
+    }
+
+    let mut pos = 4;
+    let read_varint = |pos: &mut usize| -> Result<usize, PatchFormatError> {
+        let too_large = || invalid("a number is too large");
+        let mut value: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let byte = *body
+                .get(*pos)
+                .ok_or_else(|| invalid("it ends in the middle of a number"))?;
+            *pos += 1;
+            let digit = (byte as usize & 0x7F)
+                .checked_mul(shift)
+                .ok_or_else(too_large)?;
+            value = value.checked_add(digit).ok_or_else(too_large)?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_mul(1 << 7).ok_or_else(too_large)?;
+            value = value.checked_add(shift).ok_or_else(too_large)?;
+        }
+    };
+
+    let source_size = read_varint(&mut pos)?;
+    let target_size = read_varint(&mut pos)?;
+    let metadata_size = read_varint(&mut pos)?;
+    if source_size != source.len() {
+        return Err(PatchFormatError::Checksum("source"));
+    }
+    pos = pos
+        .checked_add(metadata_size)
+        .filter(|pos| *pos <= body.len())
+        .ok_or_else(|| invalid("its metadata is longer than the patch"))?;
+
+    // the sizes come from the patch, so only reserve what the files could hold
+    let mut target: Vec<u8> = Vec::with_capacity(target_size.min(source.len() + body.len()));
+    let mut source_relative_offset: usize = 0;
+    let mut target_relative_offset: usize = 0;
+    let out_of_bounds = || invalid("it reads outside of the files");
+    let span = |start: usize, len: usize| start.checked_add(len).map(|end| start..end);
+    while pos < body.len() {
+        let data = read_varint(&mut pos)?;
+        let len = (data >> 2) + 1;
+        if span(target.len(), len).is_none_or(|written| written.end > target_size) {
+            return Err(invalid("it writes past the end of the target"));
+        }
+        match data & 3 {
+            BPS_SOURCE_READ => {
+                let bytes = span(target.len(), len).and_then(|range| source.get(range));
+                target.extend_from_slice(bytes.ok_or_else(out_of_bounds)?);
+            }
+            BPS_TARGET_READ => {
+                let bytes = span(pos, len).and_then(|range| body.get(range));
+                target.extend_from_slice(bytes.ok_or_else(out_of_bounds)?);
+                pos += len;
+            }
+            action => {
+                let offset = read_varint(&mut pos)?;
+                let relative = if action == BPS_TARGET_COPY {
+                    &mut target_relative_offset
+                } else {
+                    &mut source_relative_offset
+                };
+                *relative = if offset & 1 == 0 {
+                    relative.checked_add(offset >> 1)
+                } else {
+                    relative.checked_sub(offset >> 1)
+                }
+                .ok_or_else(out_of_bounds)?;
+                if action != BPS_TARGET_COPY {
+                    let bytes = span(*relative, len)
+                        .and_then(|range| source.get(range))
+                        .ok_or_else(out_of_bounds)?;
+                    target.extend_from_slice(bytes);
+                    *relative += len;
+                } else {
+                    // target copies may overlap what they write, byte by byte
+                    for _ in 0..len {
+                        let byte = *target.get(*relative).ok_or_else(out_of_bounds)?;
+                        target.push(byte);
+                        *relative += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    if target.len() != target_size || crc32fast::hash(&target) != checksum(1) {
+        return Err(PatchFormatError::Checksum("target"));
+    }
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edits() -> Vec<Edit> {
+        vec![
+            Edit {
+                range: 1..3,
+                bytes: vec![0xAA, 0xBB],
+            },
+            Edit {
+                range: 5..6,
+                bytes: vec![0xCC, 0xDD, 0xEE],
+            },
+            Edit {
+                range: 8..10,
+                bytes: vec![],
+            },
+        ]
+    }
+
+    #[test]
+    fn test_round_trips() {
+        let source: Vec<u8> = (0..12).collect();
+        let target = apply_edits(&source, &edits());
+        assert_eq!(
+            target,
+            vec![0, 0xAA, 0xBB, 3, 4, 0xCC, 0xDD, 0xEE, 6, 7, 10, 11]
+        );
+
+        for format in [PatchFormat::Ips, PatchFormat::Bps] {
+            let patch = encode(format, &source, &edits()).unwrap();
+            assert_eq!(
+                apply(format, &source, &patch).unwrap(),
+                target,
+                "{:?}",
+                format
+            );
+        }
+
+        let shorter = [Edit {
+            range: 2..6,
+            bytes: vec![],
+        }];
+        let patch = encode(PatchFormat::Ips, &source, &shorter).unwrap();
+        assert_eq!(
+            apply(PatchFormat::Ips, &source, &patch).unwrap(),
+            apply_edits(&source, &shorter)
+        );
+    }
+
+    #[test]
+    fn test_ips_records() {
+        let patch = encode_ips(b"\x00\x01\x02\x03", b"\x00\xFF\x02\x03").unwrap();
+        assert_eq!(patch, b"PATCH\x00\x00\x01\x00\x01\xFFEOF");
+
+        // an RLE record from another tool
+        let rle = b"PATCH\x00\x00\x01\x00\x00\x00\x03\xEEEOF";
+        assert_eq!(apply_ips(b"\x00\x01", rle).unwrap(), b"\x00\xEE\xEE\xEE");
+        assert!(apply_ips(b"\x00", b"PATCH\x00\x00").is_err());
+    }
+
+    #[test]
+    fn test_bps_checks_the_source() {
+        let source: Vec<u8> = (0..12).collect();
+        let patch = encode(PatchFormat::Bps, &source, &edits()).unwrap();
+        assert!(matches!(
+            apply_bps(&source[1..], &patch),
+            Err(PatchFormatError::Checksum("source"))
+        ));
+    }
+
+    #[test]
+    fn test_bps_rejects_malformed_patches() {
+        let source = b"abcd";
+        // a patch with valid checksums around `numbers`
+        let patch = |numbers: &[usize]| {
+            let mut patch = b"BPS1".to_vec();
+            for number in numbers {
+                write_varint(&mut patch, *number);
+            }
+            patch.extend(crc32fast::hash(source).to_le_bytes());
+            patch.extend(0u32.to_le_bytes());
+            patch.extend(crc32fast::hash(&patch).to_le_bytes());
+            patch
+        };
+        let invalid = |numbers: &[usize]| {
+            matches!(
+                apply_bps(source, &patch(numbers)),
+                Err(PatchFormatError::Invalid("BPS", _))
+            )
+        };
+
+        // metadata longer than the patch
+        assert!(invalid(&[4, 4, usize::MAX >> 8]));
+        // a huge target size isn't allocated up front
+        assert!(invalid(&[
+            4,
+            usize::MAX >> 8,
+            0,
+            (8 - 1) << 2 | BPS_SOURCE_READ
+        ]));
+        // reads past the end of the patch or the source
+        assert!(invalid(&[
+            4,
+            usize::MAX >> 8,
+            0,
+            (usize::MAX >> 2) << 2 | BPS_TARGET_READ
+        ]));
+        assert!(invalid(&[4, 4, 0, BPS_SOURCE_COPY, usize::MAX - 1]));
+        // writes more than the target size
+        assert!(invalid(&[4, 2, 0, (4 - 1) << 2 | BPS_SOURCE_READ]));
+    }
+}
@@ -0,0 +1,352 @@
+//! Patch sets: several named binary patches in one TOML file, applied to a
+//! file only if every patch and hash checks out.
+//!
+//! ```toml
+//! input_sha256 = "..."   # optional, hash of the file before patching
+//! output_sha256 = "..."  # optional, hash of the file after patching
+//!
+//! [[patch]]
+//! name = "skip-crc-check"
+//! search = "DE AD ?? EF"
+//! replace = "DE 00 ?? EF"
+//! count = 1                  # expected number of matches, default at least one
+//! range = [0x1000, 0x8000]   # optional
+//! ```
+//!
+//! Patches also take `align`, `at` and `expect` like the command line, and
+//! their own `input_sha256` / `output_sha256` for the data they see.
+//!
+//! `reno patch apply` also takes IPS and BPS files, see [`patch_format`].
+
+use crate::replace::atomic::write_atomic;
+use crate::replace::binary::{select_matches, BinaryPatch, BinaryPattern};
+use crate::replace::contents::{ContentOptions, DoContentError};
+use crate::replace::patch_format::{self, PatchFormat, PatchFormatError};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PatchSet {
+    pub input_sha256: Option<String>,
+    pub output_sha256: Option<String>,
+    #[serde(rename = "patch", default)]
+    pub patches: Vec<Patch>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Patch {
+    pub name: String,
+    pub search: String,
+    pub replace: String,
+    pub count: Option<usize>,
+    pub range: Option<[usize; 2]>,
+    pub align: Option<usize>,
+    pub at: Option<usize>,
+    pub expect: Option<String>,
+    pub input_sha256: Option<String>,
+    pub output_sha256: Option<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum PatchSetError {
+    #[error("Could not read {0}: {1}")]
+    Read(String, #[source] io::Error),
+    #[error("Could not write {0}: {1}")]
+    Write(String, #[source] io::Error),
+    #[error("Invalid patch set {0}: {1}")]
+    Parse(String, #[source] toml::de::Error),
+    #[error("{0}")]
+    Patch(#[from] DoContentError),
+    #[error("Expected {0} matches but found {1}")]
+    Count(usize, usize),
+    #[error("The {0} hash is {1}, expected {2}")]
+    Hash(&'static str, String, String),
+    #[error("{0}")]
+    Format(#[from] PatchFormatError),
+}
+
+/// What happened to one patch of a set.
+#[derive(Debug)]
+pub struct PatchResult {
+    pub name: String,
+    /// Offsets of the matches, or why the patch can't be applied.
+    pub outcome: Result<Vec<usize>, PatchSetError>,
+}
+
+/// The outcome of applying a patch set.
+#[derive(Debug)]
+pub struct PatchSetReport {
+    /// Problems with the set as a whole, like a wrong input hash.
+    pub errors: Vec<PatchSetError>,
+    pub patches: Vec<PatchResult>,
+    /// The patched data, only if every patch and hash checked out.
+    pub patched: Option<Vec<u8>>,
+}
+
+impl PatchSet {
+    pub fn load(path: &Path) -> Result<PatchSet, PatchSetError> {
+        let text = fs::read_to_string(path)
+            .map_err(|err| PatchSetError::Read(path.display().to_string(), err))?;
+        toml::from_str(&text).map_err(|err| PatchSetError::Parse(path.display().to_string(), err))
+    }
+
+    /// Applies every patch in order to a copy of `data`, each one seeing the
+    /// result of the ones before it.
+    pub fn apply(&self, data: &[u8]) -> PatchSetReport {
+        let mut errors = vec![];
+        if let Err(err) = check_hash("input", data, self.input_sha256.as_deref()) {
+            errors.push(err);
+        }
+
+        let mut patched = data.to_vec();
+        let mut patches = vec![];
+        for patch in &self.patches {
+            let outcome = patch.apply(&patched).map(|(offsets, result)| {
+                patched = result;
+                offsets
+            });
+            patches.push(PatchResult {
+                name: patch.name.clone(),
+                outcome,
+            });
+        }
+
+        let all_applied = patches.iter().all(|patch| patch.outcome.is_ok());
+        if all_applied {
+            if let Err(err) = check_hash("output", &patched, self.output_sha256.as_deref()) {
+                errors.push(err);
+            }
+        }
+        let verified = all_applied && errors.is_empty();
+        PatchSetReport {
+            errors,
+            patches,
+            patched: verified.then_some(patched),
+        }
+    }
+}
+
+impl Patch {
+    /// The offsets of the matches and the patched data.
+    fn apply(&self, data: &[u8]) -> Result<(Vec<usize>, Vec<u8>), PatchSetError> {
+        check_hash("input", data, self.input_sha256.as_deref())?;
+
+        let pattern = BinaryPattern::parse(&self.search, &self.replace, false)?;
+        let options = ContentOptions {
+            range: self.range.map(|[start, end]| start..end),
+            align: self.align,
+            at: self.at,
+            expect: self.expect.clone(),
+            ..Default::default()
+        };
+        let matches = select_matches(&pattern, data, &options)?;
+        match self.count {
+            Some(count) if count != matches.len() => {
+                return Err(PatchSetError::Count(count, matches.len()))
+            }
+            None if matches.is_empty() => return Err(DoContentError::NoMatchesFound.into()),
+            _ => {}
+        }
+
+        let mut patched = Vec::with_capacity(data.len());
+        pattern
+            .write_patched(data, &matches, &mut patched)
+            .expect("writing to memory can't fail");
+        check_hash("output", &patched, self.output_sha256.as_deref())?;
+
+        Ok((matches.iter().map(|found| found.start).collect(), patched))
+    }
+}
+
+/// Applies the patch set at `set_path` to `file` and prints the result of
+/// every patch. The file (or `output`, if given) is only written when the
+/// whole set checked out and this isn't a dry run. Returns whether it did.
+///
+/// Files ending in `.ips` or `.bps` are applied as a whole instead.
+pub fn apply_file(
+    set_path: &Path,
+    file: &Path,
+    dry: bool,
+    output: Option<&Path>,
+) -> Result<bool, PatchSetError> {
+    let data =
+        fs::read(file).map_err(|err| PatchSetError::Read(file.display().to_string(), err))?;
+
+    let format = set_path
+        .extension()
+        .and_then(|extension| PatchFormat::from_extension(&extension.to_string_lossy()));
+    if let Some(format) = format {
+        let patch = fs::read(set_path)
+            .map_err(|err| PatchSetError::Read(set_path.display().to_string(), err))?;
+        println!("{:?}", file);
+        let patched = match patch_format::apply(format, &data, &patch) {
+            Ok(patched) => patched,
+            Err(err) => {
+                println!("    FAILED {}", err);
+                println!("The patch does not apply, nothing was written");
+                return Ok(false);
+            }
+        };
+        println!("    ok     {} bytes -> {} bytes", data.len(), patched.len());
+        return write_patched(file, patched, dry, output);
+    }
+
+    let patch_set = PatchSet::load(set_path)?;
+    let report = patch_set.apply(&data);
+
+    println!("{:?}", file);
+    for patch in &report.patches {
+        match &patch.outcome {
+            Ok(offsets) => println!(
+                "    ok     {}: {} at {}",
+                patch.name,
+                if offsets.len() == 1 {
+                    "match"
+                } else {
+                    "matches"
+                },
+                offsets
+                    .iter()
+                    .map(|offset| format!("{:#x}", offset))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Err(err) => println!("    FAILED {}: {}", patch.name, err.to_string().trim_end()),
+        }
+    }
+    for err in &report.errors {
+        println!("    FAILED {}", err);
+    }
+
+    match report.patched {
+        Some(patched) => write_patched(file, patched, dry, output),
+        None => {
+            println!("The patch set does not apply, nothing was written");
+            Ok(false)
+        }
+    }
+}
+
+fn write_patched(
+    file: &Path,
+    patched: Vec<u8>,
+    dry: bool,
+    output: Option<&Path>,
+) -> Result<bool, PatchSetError> {
+    if dry {
+        println!("<dry> the patch applies");
+        return Ok(true);
+    }
+
+    let target: PathBuf = output.unwrap_or(file).to_path_buf();
+    let written = if output.is_some() {
+        fs::write(&target, &patched)
+    } else {
+        write_atomic(&target, &patched)
+    };
+    written.map_err(|err| PatchSetError::Write(target.display().to_string(), err))?;
+    println!("Wrote {:?}", target);
+    Ok(true)
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+fn check_hash(
+    what: &'static str,
+    data: &[u8],
+    expected: Option<&str>,
+) -> Result<(), PatchSetError> {
+    let expected = match expected {
+        Some(expected) => expected.to_ascii_lowercase(),
+        None => return Ok(()),
+    };
+    let actual = sha256_hex(data);
+    if actual != expected {
+        return Err(PatchSetError::Hash(what, actual, expected));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(toml: &str) -> PatchSet {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn test_apply_in_order() {
+        let patch_set = set(r#"
+            [[patch]]
+            name = "first"
+            search = "AA BB"
+            replace = "AA CC"
+            count = 2
+
+            [[patch]]
+            name = "second"
+            search = "AA CC"
+            replace = "00 00"
+            at = 2
+            expect = "AA CC"
+        "#);
+        let report = patch_set.apply(b"\xAA\xBB\xAA\xBB");
+        assert!(report.errors.is_empty());
+        assert_eq!(report.patches[0].outcome.as_ref().unwrap(), &vec![0, 2]);
+        assert_eq!(report.patches[1].outcome.as_ref().unwrap(), &vec![2]);
+        assert_eq!(report.patched.unwrap(), b"\xAA\xCC\x00\x00");
+    }
+
+    #[test]
+    fn test_nothing_is_patched_on_failure() {
+        let data = b"\xAA\xBB";
+        let patch_set = set(&format!(
+            r#"
+            input_sha256 = "{}"
+
+            [[patch]]
+            name = "fine"
+            search = "AA"
+            replace = "00"
+
+            [[patch]]
+            name = "missing"
+            search = "CC"
+            replace = "00"
+            "#,
+            sha256_hex(data)
+        ));
+        let report = patch_set.apply(data);
+        assert!(report.errors.is_empty());
+        assert!(report.patches[0].outcome.is_ok());
+        assert!(matches!(
+            report.patches[1].outcome,
+            Err(PatchSetError::Patch(DoContentError::NoMatchesFound))
+        ));
+        assert!(report.patched.is_none());
+
+        let wrong_hash = set(r#"
+            output_sha256 = "00"
+
+            [[patch]]
+            name = "fine"
+            search = "AA"
+            replace = "00"
+        "#);
+        let report = wrong_hash.apply(data);
+        assert!(matches!(
+            report.errors[..],
+            [PatchSetError::Hash("output", ..)]
+        ));
+        assert!(report.patched.is_none());
+    }
+}
@@ -0,0 +1,68 @@
+//! `--search-file` and `--replace-file`: patterns too long for the command line.
+
+use crate::replace::binary::to_hex_string;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Extensions of files holding a signature as text, anything else is raw bytes.
+const SIGNATURE_EXTENSIONS: [&str; 3] = ["hex", "sig", "txt"];
+
+/// Reads a binary signature from `path`. Raw files match byte for byte, text
+/// files (`.hex`, `.sig`, `.txt`) are signatures that may span several lines
+/// and have `#` comments.
+pub fn load_signature(path: &Path) -> io::Result<String> {
+    let is_text = path.extension().is_some_and(|extension| {
+        SIGNATURE_EXTENSIONS.contains(&extension.to_string_lossy().to_ascii_lowercase().as_str())
+    });
+    if !is_text {
+        return Ok(to_hex_string(&fs::read(path)?));
+    }
+    Ok(strip_comments(&fs::read_to_string(path)?))
+}
+
+/// Reads a text mode replacement from `path`, used literally, so `$` in the
+/// file doesn't refer to capture groups.
+pub fn load_replacement_text(path: &Path) -> io::Result<String> {
+    Ok(fs::read_to_string(path)?.replace('$', "$$"))
+}
+
+/// Drops `#` comments outside of `"text"` and joins the lines with spaces.
+fn strip_comments(text: &str) -> String {
+    let mut signature = String::with_capacity(text.len());
+    let mut in_text = false;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if in_text => {
+                signature.push(c);
+                signature.extend(chars.next());
+            }
+            '"' => {
+                in_text = !in_text;
+                signature.push(c);
+            }
+            '#' if !in_text => {
+                chars.by_ref().find(|c| *c == '\n');
+                signature.push(' ');
+            }
+            '\r' | '\n' if !in_text => signature.push(' '),
+            c => signature.push(c),
+        }
+    }
+    signature
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_comments() {
+        let text = "# the prologue\r\nDE AD ?? # a register\nEF \"# not a comment\\\"\"\n";
+        assert_eq!(
+            strip_comments(text).split_whitespace().collect::<Vec<_>>(),
+            ["DE", "AD", "??", "EF", "\"#", "not", "a", "comment\\\"\""]
+        );
+    }
+}
@@ -0,0 +1,1007 @@
+//! The binary signature language.
+//!
+//! A signature is a list of hex bytes like `DE AD ?? EF` (or `\xDE\xAD\x??\xEF`,
+//! spaces and `\x` may be mixed or left out), extended with:
+//!
+//! - `D?` and `?F` to only match the high or low nibble of a byte, and
+//!   `7F&0F` to only match the bits set in the mask after the `&`.
+//! - `"text"` for UTF-8 text and `u"text"` for UTF-16LE text, with the escapes
+//!   `\"`, `\\`, `\n`, `\r`, `\t` and `\0`.
+//! - Typed numbers like `u32le:0xDEADBEEF`, `i16be:-2` or `f32le:1.5`. Integers
+//!   (`u`/`i`) and floats (`f`) take a size of 8, 16, 32 or 64 bits and, unless
+//!   they are 8 bits, `le` or `be` for their byte order.
+//! - In a replacement, `$1` or `${1}` copies what the first capture matched,
+//!   see [`BinaryPattern`](crate::replace::binary::BinaryPattern).
+//! - `[DE ??]*4` repeats a byte or group exactly 4 times, `*2..8` between 2 and 8
+//!   times, `*2..` at least twice and `...` any number of times. Repetitions
+//!   match as few times as possible.
+//! - `[utf8:[A-z0-9]+]*10` matches 10 bytes that decode as UTF-8 (or any other
+//!   encoding label, e.g. `utf16le`, `latin1`) and fully match the regex. For
+//!   UTF-8 the length may be left out, the regex then decides how far it goes.
+//!
+//! ```text
+//! signature  = element*
+//! element    = atom repetition?
+//! atom       = byte | "[" signature "]" | "[" label ":" regex "]" ("*" number)?
+//!            | "u"? "\"" text "\"" | type ":" value | "$" "{"? number "}"?
+//! byte       = "\x"? nibble nibble ("&" hex hex)?
+//! nibble     = hex | "?"
+//! repetition = "..." | "*" number (".." number?)?
+//! ```
+
+use crate::replace::contents::DoContentError;
+use encoding_rs::Encoding;
+use regex::bytes::Regex as BytesRegex;
+use regex::Regex;
+use std::cell::RefCell;
+
+/// What a single byte of a signature accepts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteMatcher {
+    /// `DE`
+    Exact(u8),
+    /// `??`
+    Any,
+    /// `D?`, `?F` or `7F&0F`, the bits set in `mask` have to equal `value`.
+    Masked { value: u8, mask: u8 },
+}
+
+impl ByteMatcher {
+    /// The simplest matcher for `value` under `mask`.
+    pub fn masked(value: u8, mask: u8) -> ByteMatcher {
+        match mask {
+            0x00 => ByteMatcher::Any,
+            0xFF => ByteMatcher::Exact(value),
+            mask => ByteMatcher::Masked {
+                value: value & mask,
+                mask,
+            },
+        }
+    }
+
+    pub fn matches(&self, byte: u8) -> bool {
+        match *self {
+            ByteMatcher::Exact(value) => byte == value,
+            ByteMatcher::Any => true,
+            ByteMatcher::Masked { value, mask } => byte & mask == value,
+        }
+    }
+
+    /// The byte this always matches, `None` for (partial) wildcards.
+    pub fn literal(&self) -> Option<u8> {
+        match *self {
+            ByteMatcher::Exact(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn is_wildcard(&self) -> bool {
+        *self == ByteMatcher::Any
+    }
+
+    /// Used in a replacement: overwrites the bits this matcher defines and
+    /// keeps the rest of `byte`.
+    pub fn apply(&self, byte: u8) -> u8 {
+        match *self {
+            ByteMatcher::Exact(value) => value,
+            ByteMatcher::Any => byte,
+            ByteMatcher::Masked { value, mask } => byte & !mask | value,
+        }
+    }
+}
+
+/// How many bytes of a match may differ from the signature, and which did.
+///
+/// Only bytes matched by a [`ByteMatcher`] count, text has to match exactly.
+#[derive(Debug, Default)]
+pub struct Tolerance {
+    max: usize,
+    mismatches: RefCell<Vec<usize>>,
+}
+
+impl Tolerance {
+    pub fn new(max: usize) -> Tolerance {
+        Tolerance {
+            max,
+            mismatches: RefCell::new(vec![]),
+        }
+    }
+
+    /// The offsets of the bytes that didn't match.
+    pub fn into_mismatches(self) -> Vec<usize> {
+        self.mismatches.into_inner()
+    }
+
+    /// Accepts the byte at `pos` although it doesn't match, if there is budget
+    /// left and the rest matches.
+    fn mismatch(&self, pos: usize, next: &mut dyn FnMut(usize) -> bool) -> bool {
+        if self.mismatches.borrow().len() >= self.max {
+            return false;
+        }
+        self.mismatches.borrow_mut().push(pos);
+        if next(pos + 1) {
+            return true;
+        }
+        self.mismatches.borrow_mut().pop();
+        false
+    }
+
+    fn checkpoint(&self) -> usize {
+        self.mismatches.borrow().len()
+    }
+
+    fn rewind(&self, checkpoint: usize) {
+        self.mismatches.borrow_mut().truncate(checkpoint);
+    }
+}
+
+/// Text in some encoding that has to match a regex.
+#[derive(Debug, Clone)]
+pub struct TextMatcher {
+    pub encoding: &'static Encoding,
+    /// Number of bytes the text takes up, `None` lets the regex decide (UTF-8 only).
+    pub len: Option<usize>,
+    regex: Regex,
+    bytes_regex: BytesRegex,
+}
+
+impl TextMatcher {
+    fn match_at(&self, haystack: &[u8], pos: usize) -> Option<usize> {
+        match self.len {
+            Some(len) => {
+                let window = haystack.get(pos..pos + len)?;
+                let text = self
+                    .encoding
+                    .decode_without_bom_handling_and_without_replacement(window)?;
+                self.regex.is_match(&text).then_some(pos + len)
+            }
+            None => self
+                .bytes_regex
+                .find(&haystack[pos..])
+                .filter(|found| found.end() > 0)
+                .map(|found| pos + found.end()),
+        }
+    }
+}
+
+/// One element of a parsed signature.
+#[derive(Debug, Clone)]
+pub enum Node {
+    Byte(ByteMatcher),
+    Group(Vec<Node>),
+    Text(TextMatcher),
+    Repeat {
+        node: Box<Node>,
+        min: usize,
+        max: Option<usize>,
+    },
+    /// `$1` in a replacement, what the first capture of the search matched.
+    Capture(usize),
+}
+
+impl Node {
+    /// The number of bytes this node always matches, `None` if it varies.
+    pub fn fixed_len(&self) -> Option<usize> {
+        match self {
+            Node::Byte(_) => Some(1),
+            Node::Group(nodes) => nodes.iter().map(Node::fixed_len).sum(),
+            Node::Text(text) => text.len,
+            Node::Repeat { node, min, max } if Some(*min) == *max => {
+                node.fixed_len().map(|len| len * min)
+            }
+            Node::Repeat { .. } => None,
+            Node::Capture(_) => None,
+        }
+    }
+
+    /// The fewest bytes this node can match.
+    pub fn min_len(&self) -> usize {
+        match self {
+            Node::Byte(_) => 1,
+            Node::Group(nodes) => nodes.iter().map(Node::min_len).sum(),
+            Node::Text(text) => text.len.unwrap_or(1),
+            Node::Repeat { node, min, .. } => node.min_len() * min,
+            Node::Capture(_) => 0,
+        }
+    }
+
+    /// The number of bytes this node always compares against a value, the
+    /// ones that aren't `??` or text.
+    pub fn min_literal_len(&self) -> usize {
+        match self {
+            Node::Byte(ByteMatcher::Any) => 0,
+            Node::Byte(_) => 1,
+            Node::Group(nodes) => nodes.iter().map(Node::min_literal_len).sum(),
+            Node::Text(_) => 0,
+            Node::Repeat { node, min, .. } => node.min_literal_len() * min,
+            Node::Capture(_) => 0,
+        }
+    }
+
+    /// The byte matchers of a fixed length node, `None` for variable length
+    /// nodes and text, which can't be compared byte by byte.
+    pub fn flatten(&self) -> Option<Vec<ByteMatcher>> {
+        match self {
+            Node::Byte(byte) => Some(vec![*byte]),
+            Node::Group(nodes) => nodes
+                .iter()
+                .map(Node::flatten)
+                .collect::<Option<Vec<_>>>()
+                .map(|bytes| bytes.concat()),
+            Node::Text(_) => None,
+            Node::Repeat { node, min, max } if Some(*min) == *max => {
+                node.flatten().map(|bytes| bytes.repeat(*min))
+            }
+            Node::Repeat { .. } => None,
+            Node::Capture(_) => None,
+        }
+    }
+
+    /// Whether the search element is numbered as a capture, which is
+    /// everything but plain bytes.
+    pub fn is_capture(&self) -> bool {
+        !self
+            .flatten()
+            .is_some_and(|bytes| bytes.iter().all(|byte| byte.literal().is_some()))
+    }
+
+    /// Whether the node only consists of `??`.
+    pub fn is_wildcard(&self) -> bool {
+        match self {
+            Node::Byte(byte) => byte.is_wildcard(),
+            Node::Group(nodes) => nodes.iter().all(Node::is_wildcard),
+            Node::Text(_) => false,
+            Node::Repeat { node, .. } => node.is_wildcard(),
+            Node::Capture(_) => false,
+        }
+    }
+
+    /// Calls `next` with every position this node can end at when it starts at
+    /// `pos`, in order of preference, until `next` accepts one.
+    pub fn match_at(
+        &self,
+        haystack: &[u8],
+        pos: usize,
+        tolerance: &Tolerance,
+        next: &mut dyn FnMut(usize) -> bool,
+    ) -> bool {
+        match self {
+            Node::Byte(byte) => match haystack.get(pos) {
+                Some(found) if byte.matches(*found) => next(pos + 1),
+                Some(_) => tolerance.mismatch(pos, next),
+                None => false,
+            },
+            Node::Group(nodes) => match_sequence(nodes, haystack, pos, tolerance, next),
+            Node::Text(text) => text.match_at(haystack, pos).is_some_and(next),
+            Node::Repeat { node, min, max } => match node.fixed_len() {
+                Some(len) if len > 0 => {
+                    match_fixed_repeat(node, len, *min, *max, haystack, pos, tolerance, next)
+                }
+                _ => match_repeat(node, *min, *max, 0, haystack, pos, tolerance, next),
+            },
+            // only used in replacements
+            Node::Capture(_) => false,
+        }
+    }
+}
+
+fn match_sequence(
+    nodes: &[Node],
+    haystack: &[u8],
+    pos: usize,
+    tolerance: &Tolerance,
+    next: &mut dyn FnMut(usize) -> bool,
+) -> bool {
+    match nodes.split_first() {
+        None => next(pos),
+        Some((first, rest)) => first.match_at(haystack, pos, tolerance, &mut |end| {
+            match_sequence(rest, haystack, end, tolerance, next)
+        }),
+    }
+}
+
+/// Repetition of a node with exactly one way to match, done in a loop so long
+/// runs like `??...` don't recurse once per byte.
+#[allow(clippy::too_many_arguments)]
+fn match_fixed_repeat(
+    node: &Node,
+    len: usize,
+    min: usize,
+    max: Option<usize>,
+    haystack: &[u8],
+    pos: usize,
+    tolerance: &Tolerance,
+    next: &mut dyn FnMut(usize) -> bool,
+) -> bool {
+    let checkpoint = tolerance.checkpoint();
+    let mut end = pos;
+    let mut count = 0;
+    loop {
+        if count >= min && next(end) {
+            return true;
+        }
+        if max.is_some_and(|max| count >= max)
+            || end + len > haystack.len()
+            || !node.match_at(haystack, end, tolerance, &mut |_| true)
+        {
+            tolerance.rewind(checkpoint);
+            return false;
+        }
+        end += len;
+        count += 1;
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn match_repeat(
+    node: &Node,
+    min: usize,
+    max: Option<usize>,
+    count: usize,
+    haystack: &[u8],
+    pos: usize,
+    tolerance: &Tolerance,
+    next: &mut dyn FnMut(usize) -> bool,
+) -> bool {
+    if count >= min && next(pos) {
+        return true;
+    }
+    if max.is_some_and(|max| count >= max) {
+        return false;
+    }
+    node.match_at(haystack, pos, tolerance, &mut |end| {
+        end > pos && match_repeat(node, min, max, count + 1, haystack, end, tolerance, next)
+    })
+}
+
+/// Matches `nodes` one after the other starting at `pos` and returns where
+/// each of them ended. Up to the maximum of `tolerance` bytes may differ, it
+/// records which did.
+pub fn match_nodes(
+    nodes: &[Node],
+    haystack: &[u8],
+    pos: usize,
+    tolerance: &Tolerance,
+) -> Option<Vec<usize>> {
+    fn go(
+        nodes: &[Node],
+        haystack: &[u8],
+        pos: usize,
+        tolerance: &Tolerance,
+        ends: &mut Vec<usize>,
+    ) -> bool {
+        match nodes.split_first() {
+            None => true,
+            Some((first, rest)) => first.match_at(haystack, pos, tolerance, &mut |end| {
+                ends.push(end);
+                if go(rest, haystack, end, tolerance, ends) {
+                    return true;
+                }
+                ends.pop();
+                false
+            }),
+        }
+    }
+
+    let mut ends = Vec::with_capacity(nodes.len());
+    go(nodes, haystack, pos, tolerance, &mut ends).then_some(ends)
+}
+
+/// Looks up an encoding by its WHATWG label, also accepting the common
+/// spellings without a dash (`utf16le`) and `ascii`.
+fn encoding_for_label(label: &str) -> Option<&'static Encoding> {
+    let label = label.to_ascii_lowercase();
+    let label = match label.as_str() {
+        "ascii" => "us-ascii",
+        "utf16" | "utf16le" => "utf-16le",
+        "utf16be" => "utf-16be",
+        label => label,
+    };
+    Encoding::for_label(label.as_bytes())
+}
+
+/// A group matching exactly `bytes`.
+fn literal_bytes(bytes: &[u8]) -> Node {
+    Node::Group(
+        bytes
+            .iter()
+            .map(|byte| Node::Byte(ByteMatcher::Exact(*byte)))
+            .collect(),
+    )
+}
+
+/// Encodes `value` as the number type `ty`, e.g. `u32le`, `i8` or `f64be`.
+///
+/// Errors say whether they are about the type (`true`) or the value.
+fn encode_number(ty: &str, value: &str) -> Result<Vec<u8>, (bool, String)> {
+    let type_error = || {
+        (
+            true,
+            format!(
+                "unknown type `{}`, expected e.g. `u8`, `i16le`, `u32be` or `f64le`",
+                ty
+            ),
+        )
+    };
+    let (kind, rest) = ty.split_at(1);
+    let (bits, little_endian) = match rest {
+        "8" => (Some(8), true),
+        _ if rest.ends_with("le") => (rest[..rest.len() - 2].parse().ok(), true),
+        _ if rest.ends_with("be") => (rest[..rest.len() - 2].parse().ok(), false),
+        _ => (None, true),
+    };
+    let bits: u32 = match bits {
+        Some(bits) if [8, 16, 32, 64].contains(&bits) => bits,
+        _ => return Err(type_error()),
+    };
+    let value_error = |message: &str| (false, format!("`{}` {} for `{}`", value, message, ty));
+
+    let mut bytes = match kind {
+        "f" if bits == 32 => value
+            .parse::<f32>()
+            .map_err(|_| value_error("is not a number"))?
+            .to_le_bytes()
+            .to_vec(),
+        "f" if bits == 64 => value
+            .parse::<f64>()
+            .map_err(|_| value_error("is not a number"))?
+            .to_le_bytes()
+            .to_vec(),
+        "u" | "i" => {
+            let (negative, digits) = match value.strip_prefix('-') {
+                Some(digits) => (true, digits),
+                None => (false, value.strip_prefix('+').unwrap_or(value)),
+            };
+            let digits = digits.replace('_', "");
+            let (radix, digits) = match digits.get(..2) {
+                Some("0x" | "0X") => (16, &digits[2..]),
+                Some("0b" | "0B") => (2, &digits[2..]),
+                _ => (10, &digits[..]),
+            };
+            let magnitude = u128::from_str_radix(digits, radix)
+                .map_err(|_| value_error("is not an integer"))?;
+            if magnitude > u64::MAX as u128 {
+                return Err(value_error("is out of range"));
+            }
+            let number = if negative {
+                -(magnitude as i128)
+            } else {
+                magnitude as i128
+            };
+            let (min, max) = if kind == "u" {
+                (0, (1i128 << bits) - 1)
+            } else {
+                (-(1i128 << (bits - 1)), (1i128 << (bits - 1)) - 1)
+            };
+            if number < min || number > max {
+                return Err(value_error("is out of range"));
+            }
+            number.to_le_bytes()[..bits as usize / 8].to_vec()
+        }
+        _ => return Err(type_error()),
+    };
+    if !little_endian {
+        bytes.reverse();
+    }
+    Ok(bytes)
+}
+
+/// Parses a search signature into its nodes.
+pub fn parse_signature(signature: &str) -> Result<Vec<Node>, DoContentError> {
+    parse(signature, false)
+}
+
+/// Parses the replacement side, which may also refer to captures with `$1`.
+pub fn parse_replacement(signature: &str) -> Result<Vec<Node>, DoContentError> {
+    parse(signature, true)
+}
+
+fn parse(signature: &str, allow_captures: bool) -> Result<Vec<Node>, DoContentError> {
+    let mut parser = Parser {
+        signature,
+        chars: signature.chars().collect(),
+        pos: 0,
+        allow_captures,
+    };
+    let nodes = parser.parse_sequence()?;
+    if parser.pos < parser.chars.len() {
+        return Err(parser.error("unexpected `]` without a matching `[`"));
+    }
+    Ok(nodes)
+}
+
+struct Parser<'a> {
+    signature: &'a str,
+    chars: Vec<char>,
+    pos: usize,
+    allow_captures: bool,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &str) -> DoContentError {
+        self.error_at(self.pos, message)
+    }
+
+    fn error_at(&self, pos: usize, message: &str) -> DoContentError {
+        DoContentError::SignatureSyntax {
+            signature: self.signature.to_string(),
+            column: pos + 1,
+            message: message.to_string(),
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, s: &str) -> bool {
+        let matches = s
+            .chars()
+            .enumerate()
+            .all(|(i, c)| self.chars.get(self.pos + i) == Some(&c));
+        if matches {
+            self.pos += s.chars().count();
+        }
+        matches
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    /// Parses nodes until the end of the signature or a closing `]`.
+    fn parse_sequence(&mut self) -> Result<Vec<Node>, DoContentError> {
+        let mut nodes = vec![];
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                None | Some(']') => return Ok(nodes),
+                Some(_) => {
+                    let node = self.parse_atom()?;
+                    nodes.push(self.parse_repetition(node)?);
+                }
+            }
+        }
+    }
+
+    fn parse_atom(&mut self) -> Result<Node, DoContentError> {
+        if self.eat("[") {
+            if let Some(text) = self.parse_text()? {
+                return Ok(text);
+            }
+            let nodes = self.parse_sequence()?;
+            if !self.eat("]") {
+                return Err(self.error("missing `]`"));
+            }
+            return Ok(Node::Group(nodes));
+        }
+
+        if self.eat("\"") {
+            return self.parse_string(encoding_rs::UTF_8);
+        }
+        if self.eat("u\"") {
+            return self.parse_string(encoding_rs::UTF_16LE);
+        }
+        if let Some(label) = self.peek_label() {
+            return self.parse_number_literal(&label);
+        }
+        let start = self.pos;
+        if self.eat("$") {
+            if !self.allow_captures {
+                return Err(self.error_at(start, "captures like `$1` only work in the replacement"));
+            }
+            let braced = self.eat("{");
+            let capture = self.parse_number()?;
+            if braced && !self.eat("}") {
+                return Err(self.error("missing `}`"));
+            }
+            if capture == 0 {
+                return Err(self.error_at(start, "captures are numbered from 1"));
+            }
+            return Ok(Node::Capture(capture));
+        }
+
+        self.parse_byte().map(Node::Byte)
+    }
+
+    /// The `label` of a `label:` at the current position, if there is one.
+    fn peek_label(&self) -> Option<String> {
+        let label: String = self
+            .chars
+            .iter()
+            .skip(self.pos)
+            .take_while(|c| c.is_ascii_alphanumeric() || **c == '-' || **c == '_')
+            .collect();
+        let followed_by_colon = self.chars.get(self.pos + label.len()) == Some(&':');
+        (!label.is_empty() && followed_by_colon).then_some(label)
+    }
+
+    /// Parses the rest of a `"text"` or `u"text"` literal after the opening quote.
+    fn parse_string(&mut self, encoding: &'static Encoding) -> Result<Node, DoContentError> {
+        let mut text = String::new();
+        loop {
+            let c = match self.peek() {
+                None => return Err(self.error("missing closing `\"`")),
+                Some('"') => break,
+                Some('\\') => {
+                    self.pos += 1;
+                    match self.peek() {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('0') => '\0',
+                        _ => {
+                            return Err(self.error(
+                                "unknown escape, expected one of \\\" \\\\ \\n \\r \\t \\0",
+                            ))
+                        }
+                    }
+                }
+                Some(c) => c,
+            };
+            text.push(c);
+            self.pos += 1;
+        }
+        self.pos += 1;
+
+        let bytes: Vec<u8> = if encoding == encoding_rs::UTF_16LE {
+            text.encode_utf16().flat_map(u16::to_le_bytes).collect()
+        } else {
+            text.into_bytes()
+        };
+        Ok(literal_bytes(&bytes))
+    }
+
+    /// Parses `type:value` like `u32le:0xDEADBEEF`, `i16be:-2` or `f32le:1.5`.
+    fn parse_number_literal(&mut self, ty: &str) -> Result<Node, DoContentError> {
+        let start = self.pos;
+        self.pos += ty.len() + 1;
+        let value_start = self.pos;
+        let value: String = self
+            .chars
+            .iter()
+            .skip(self.pos)
+            .take_while(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '+' | '_'))
+            .collect();
+        let bytes = encode_number(ty, &value).map_err(|(at_type, message)| {
+            self.error_at(if at_type { start } else { value_start }, &message)
+        })?;
+        self.pos += value.len();
+        Ok(literal_bytes(&bytes))
+    }
+
+    /// Parses `DE`, `??`, `D?`, `?F` or `7F&0F`, optionally prefixed with `\x`.
+    fn parse_byte(&mut self) -> Result<ByteMatcher, DoContentError> {
+        self.eat("\\x");
+        let (high, high_mask) = self.parse_nibble()?;
+        let (low, low_mask) = self.parse_nibble()?;
+        let value = high << 4 | low;
+        let mask = high_mask << 4 | low_mask;
+
+        if self.peek() == Some('&') {
+            if mask != 0xFF {
+                return Err(self.error("a byte with `?` can't also have a mask"));
+            }
+            self.pos += 1;
+            let mask_start = self.pos;
+            let (high, high_mask) = self.parse_nibble()?;
+            let (low, low_mask) = self.parse_nibble()?;
+            if high_mask << 4 | low_mask != 0xFF {
+                return Err(self.error_at(mask_start, "a mask has to be two hex digits"));
+            }
+            return Ok(ByteMatcher::masked(value, high << 4 | low));
+        }
+        Ok(ByteMatcher::masked(value, mask))
+    }
+
+    /// A hex digit or `?`, as its value and the bits it defines.
+    fn parse_nibble(&mut self) -> Result<(u8, u8), DoContentError> {
+        let nibble = match self.peek() {
+            Some('?') => (0, 0x0),
+            Some(c) if c.is_ascii_hexdigit() => (c.to_digit(16).unwrap() as u8, 0xF),
+            Some(c) if c.is_whitespace() => {
+                return Err(self.error("a byte needs two hex digits, like `0F`"))
+            }
+            Some(c) => {
+                return Err(self.error(&format!(
+                    "unexpected `{}`, expected a hex byte like `DE`, `D?` or `??`",
+                    c
+                )))
+            }
+            None => return Err(self.error("unexpected end, a byte needs two hex digits")),
+        };
+        self.pos += 1;
+        Ok(nibble)
+    }
+
+    /// Parses `encoding:regex]` right after a `[`, or returns `None` if the
+    /// bracket is a plain group.
+    fn parse_text(&mut self) -> Result<Option<Node>, DoContentError> {
+        let label = match self.peek_label() {
+            Some(label) => label,
+            None => return Ok(None),
+        };
+        if encoding_for_label(&label).is_none() && encode_number(&label, "0").is_ok() {
+            // a group starting with a number like `[u16le:1 00]`
+            return Ok(None);
+        }
+        let encoding = encoding_for_label(&label)
+            .ok_or_else(|| self.error(&format!("unknown encoding `{}`", label)))?;
+        self.pos += label.len() + 1;
+        let regex_start = self.pos;
+
+        // the regex runs until the `]` that closes the text, regex classes may nest brackets
+        let mut depth = 0;
+        let mut pattern = String::new();
+        loop {
+            match self.peek() {
+                None => return Err(self.error("missing `]` after the regex")),
+                Some('\\') => {
+                    pattern.push('\\');
+                    self.pos += 1;
+                    if let Some(c) = self.peek() {
+                        pattern.push(c);
+                        self.pos += 1;
+                    }
+                    continue;
+                }
+                Some('[') => depth += 1,
+                Some(']') if depth == 0 => break,
+                Some(']') => depth -= 1,
+                Some(_) => {}
+            }
+            pattern.push(self.peek().unwrap());
+            self.pos += 1;
+        }
+        self.pos += 1;
+
+        let regex_error =
+            |err: regex::Error| self.error_at(regex_start, &format!("invalid regex: {}", err));
+        let regex = Regex::new(&format!("^(?:{})$", pattern)).map_err(regex_error)?;
+        let bytes_regex = BytesRegex::new(&format!("^(?:{})", pattern)).map_err(regex_error)?;
+
+        let len = if self.eat("*") {
+            let len = self.parse_number()?;
+            if len == 0 {
+                return Err(self.error("text needs a length of at least one byte"));
+            }
+            Some(len)
+        } else if encoding == encoding_rs::UTF_8 {
+            None
+        } else {
+            return Err(self.error(&format!(
+                "`{}` text needs a byte length, e.g. `[{}:...]*10`",
+                label, label
+            )));
+        };
+
+        Ok(Some(Node::Text(TextMatcher {
+            encoding,
+            len,
+            regex,
+            bytes_regex,
+        })))
+    }
+
+    fn parse_repetition(&mut self, node: Node) -> Result<Node, DoContentError> {
+        if matches!(node, Node::Text(_) | Node::Capture(_)) {
+            return Ok(node);
+        }
+        let (min, max) = if self.eat("...") {
+            (0, None)
+        } else if self.eat("*") {
+            let min = self.parse_number()?;
+            if self.eat("..") {
+                match self.peek() {
+                    Some(c) if c.is_ascii_digit() => (min, Some(self.parse_number()?)),
+                    _ => (min, None),
+                }
+            } else {
+                (min, Some(min))
+            }
+        } else {
+            return Ok(node);
+        };
+
+        if max.is_some_and(|max| max < min) {
+            return Err(self.error("the repetition range is empty"));
+        }
+        Ok(Node::Repeat {
+            node: Box::new(node),
+            min,
+            max,
+        })
+    }
+
+    fn parse_number(&mut self) -> Result<usize, DoContentError> {
+        let digits: String = self
+            .chars
+            .iter()
+            .skip(self.pos)
+            .take_while(|c| c.is_ascii_digit())
+            .collect();
+        let number = digits
+            .parse()
+            .map_err(|_| self.error("expected a number"))?;
+        self.pos += digits.len();
+        Ok(number)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find(signature: &str, haystack: &[u8]) -> Option<Vec<usize>> {
+        match_nodes(
+            &parse_signature(signature).unwrap(),
+            haystack,
+            0,
+            &Tolerance::default(),
+        )
+    }
+
+    #[test]
+    fn test_repetitions() {
+        assert_eq!(
+            find("DE [AD]*2 EF", b"\xDE\xAD\xAD\xEF"),
+            Some(vec![1, 3, 4])
+        );
+        assert_eq!(find("DE [AD]*2 EF", b"\xDE\xAD\xEF"), None);
+        assert_eq!(
+            find("DE ??... EF", b"\xDE\x01\xEF\xEF"),
+            Some(vec![1, 2, 3])
+        );
+        assert_eq!(
+            find("DE ??*2.. EF", b"\xDE\x01\xEF\xEF"),
+            Some(vec![1, 3, 4])
+        );
+        assert_eq!(
+            find("[DE ??]*1..3 00", b"\xDE\x01\xDE\x02\x00"),
+            Some(vec![4, 5])
+        );
+    }
+
+    #[test]
+    fn test_text() {
+        let signature = "4C 79 [utf8:[A-z0-9]{4}]*4 00";
+        assert_eq!(find(signature, b"Ly4abc\x00"), Some(vec![1, 2, 6, 7]));
+        assert_eq!(find(signature, b"Ly4a-c\x00"), None);
+        assert_eq!(find("[utf8:[a-z]+] 00", b"abc\x00"), Some(vec![3, 4]));
+        assert_eq!(find("[utf16le:hi]*4", b"h\x00i\x00"), Some(vec![4]));
+    }
+
+    #[test]
+    fn test_tolerance() {
+        let fuzzy = |signature: &str, haystack: &[u8], max| {
+            let tolerance = Tolerance::new(max);
+            let nodes = parse_signature(signature).unwrap();
+            match_nodes(&nodes, haystack, 0, &tolerance).map(|_| tolerance.into_mismatches())
+        };
+        let haystack = b"\xB8\x05\x00\x00\x00\xC3";
+        assert_eq!(fuzzy("B8 01 00 00 00 C3", haystack, 0), None);
+        assert_eq!(fuzzy("B8 01 00 00 00 C3", haystack, 1), Some(vec![1]));
+        assert_eq!(fuzzy("B8 01 00 00 01 C3", haystack, 1), None);
+        assert_eq!(fuzzy("B8 ?? 00 00 01 C3", haystack, 1), Some(vec![4]));
+        // mismatches given back when backtracking aren't reported
+        assert_eq!(
+            fuzzy("[B8 01]*1..2 00 00 00 C3", haystack, 1),
+            Some(vec![1])
+        );
+        assert_eq!(
+            fuzzy("[00]... 00 C3", b"\x00\x01\x00\xC3", 1),
+            Some(vec![1])
+        );
+    }
+
+    #[test]
+    fn test_nibbles_and_masks() {
+        assert_eq!(
+            parse_signature("\\xDE AD\\x??D? ?F 7F&0F FF&80 12&FF")
+                .unwrap()
+                .iter()
+                .map(|node| match node {
+                    Node::Byte(byte) => *byte,
+                    _ => unreachable!(),
+                })
+                .collect::<Vec<_>>(),
+            vec![
+                ByteMatcher::Exact(0xDE),
+                ByteMatcher::Exact(0xAD),
+                ByteMatcher::Any,
+                ByteMatcher::Masked {
+                    value: 0xD0,
+                    mask: 0xF0
+                },
+                ByteMatcher::Masked {
+                    value: 0x0F,
+                    mask: 0x0F
+                },
+                ByteMatcher::Masked {
+                    value: 0x0F,
+                    mask: 0x0F
+                },
+                ByteMatcher::Masked {
+                    value: 0x80,
+                    mask: 0x80
+                },
+                ByteMatcher::Exact(0x12),
+            ]
+        );
+        assert_eq!(find("D? ?F", b"\xD1\x2F"), Some(vec![1, 2]));
+        assert_eq!(find("D? ?F", b"\xE1\x2F"), None);
+        assert_eq!(find("80&C0", b"\xBF"), Some(vec![1]));
+        assert_eq!(find("80&C0", b"\xFF"), None);
+    }
+
+    fn bytes(signature: &str) -> Vec<u8> {
+        parse_signature(signature)
+            .unwrap()
+            .iter()
+            .flat_map(|node| node.flatten().unwrap())
+            .map(|byte| byte.literal().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_literals() {
+        assert_eq!(bytes(r#""Hi\"\0" u"Hi""#), b"Hi\"\0H\0i\0");
+        assert_eq!(bytes("u32le:0xDEADBEEF"), vec![0xEF, 0xBE, 0xAD, 0xDE]);
+        assert_eq!(bytes("u32be:0xDEADBEEF"), vec![0xDE, 0xAD, 0xBE, 0xEF]);
+        assert_eq!(
+            bytes("i16be:-2 u8:255 i8:-128"),
+            vec![0xFF, 0xFE, 0xFF, 0x80]
+        );
+        assert_eq!(bytes("f32le:1.5"), 1.5f32.to_le_bytes());
+        assert_eq!(bytes("f64be:-2"), (-2f64).to_be_bytes());
+        assert_eq!(bytes("u64le:0b1_0000_0001"), vec![1, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(bytes("[u16le:1 00]*2"), vec![1, 0, 0, 1, 0, 0]);
+        assert_eq!(find("\"ab\"*2 00", b"abab\x00"), Some(vec![4, 5]));
+
+        assert_eq!(error_column("00 u8:256"), 7);
+        assert_eq!(error_column("00 i8:-129"), 7);
+        assert_eq!(error_column("00 u24le:1"), 4);
+        assert_eq!(error_column("00 u16:1"), 4);
+        assert_eq!(error_column("00 f32le:x"), 10);
+        assert_eq!(error_column("00 \"ab"), 7);
+        assert_eq!(error_column("00 \"\\q\""), 6);
+    }
+
+    fn error_column(signature: &str) -> usize {
+        match parse_signature(signature) {
+            Err(DoContentError::SignatureSyntax { column, .. }) => column,
+            other => panic!("expected a syntax error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_error_columns() {
+        assert_eq!(error_column("DE AD X1"), 7);
+        assert_eq!(error_column("DE A BE"), 5);
+        assert_eq!(error_column("DE D?&0F"), 6);
+        assert_eq!(error_column("DE 7F&0"), 8);
+        assert_eq!(error_column("DE AD]"), 6);
+        assert_eq!(error_column("[utf8:(]"), 7);
+
+        let message = parse_signature("DE AD X1").unwrap_err().to_string();
+        assert!(
+            message.contains("\n    DE AD X1\n          ^"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(parse_signature("DE [AD").is_err());
+        assert!(parse_signature("DE AD]").is_err());
+        assert!(parse_signature("DE A").is_err());
+        assert!(parse_signature("[nope:x]*2").is_err());
+        assert!(parse_signature("[utf16le:x]").is_err());
+        assert!(parse_signature("[AD]*3..2").is_err());
+    }
+}
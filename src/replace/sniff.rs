@@ -0,0 +1,73 @@
+//! Telling binary files from text before text mode decodes them.
+
+/// How many bytes from the start of a file are inspected.
+pub const SNIFF_LEN: usize = 8 * 1024;
+
+/// Share of control bytes above which a file is considered binary.
+const MAX_CONTROL_RATIO: f32 = 0.3;
+
+/// Below this `chardet` confidence, data that isn't UTF-8 is considered binary.
+const MIN_CHARDET_CONFIDENCE: f32 = 0.5;
+
+/// What text mode does with files that look binary.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum BinaryFiles {
+    /// Leave them alone and count them
+    #[default]
+    Skip,
+    /// Search them like any other file
+    Text,
+    /// Report them as an error
+    Error,
+}
+
+/// Whether `prefix`, the start of a file, looks like binary data: it has NUL
+/// bytes or lots of control characters, or it isn't UTF-8 and `chardet`
+/// doesn't recognize another encoding either. Files with a byte order mark
+/// are text.
+pub fn looks_binary(prefix: &[u8]) -> bool {
+    const BOMS: [&[u8]; 3] = [b"\xEF\xBB\xBF", b"\xFF\xFE", b"\xFE\xFF"];
+    if BOMS.iter().any(|bom| prefix.starts_with(bom)) {
+        return false;
+    }
+    if prefix.contains(&0) {
+        return true;
+    }
+
+    let control = prefix
+        .iter()
+        .filter(|byte| {
+            (byte.is_ascii_control() && !matches!(byte, b'\t' | b'\n' | b'\r' | 0x0C | 0x1B))
+                || **byte == 0x7F
+        })
+        .count();
+    if control as f32 > prefix.len() as f32 * MAX_CONTROL_RATIO {
+        return true;
+    }
+
+    match std::str::from_utf8(prefix) {
+        Ok(_) => false,
+        // a character cut off at the end of the prefix
+        Err(err) if err.error_len().is_none() => false,
+        Err(_) => {
+            let (_, confidence, _) = chardet::detect(prefix);
+            confidence < MIN_CHARDET_CONFIDENCE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_looks_binary() {
+        assert!(!looks_binary(b""));
+        assert!(!looks_binary(b"fn main() {\r\n\tprintln!(\"hi\");\r\n}\n"));
+        assert!(!looks_binary("gr\u{fc}\u{df}e".as_bytes()));
+        assert!(!looks_binary(&"\u{e9}".as_bytes()[..1]));
+        assert!(!looks_binary(b"\xFF\xFEh\x00i\x00"));
+        assert!(looks_binary(b"\x7FELF\x02\x01\x01\x00"));
+        assert!(looks_binary(b"\x89PNG\r\n\x1A\n\x01\x02\x03"));
+    }
+}
@@ -2,36 +2,56 @@ extern crate encoding_rs;
 extern crate encoding_rs_io;
 
 use crate::glob_walk::GlobWalkerBuilder;
+use crate::replace::binary::{parse_patch, BinaryPattern};
 use crate::replace::contents::*;
 use crate::replace::names::*;
+use crate::replace::sniff::BinaryFiles;
 
 use anyhow::Result;
 use rayon::prelude::*;
-use std::fs::File;
-use std::fs::*;
+use regex::Regex;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::str;
 use std::string::String;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Settings for a search and replace run over the globbed files.
+#[derive(Debug, Clone, Default)]
+pub struct WalkOptions {
+    pub globs: Vec<String>,
+    pub max_depth: usize,
+    /// Also walk what ignore files ignore.
+    pub no_ignore: bool,
+    /// Also walk hidden files.
+    pub hidden: bool,
+    /// Files the search or replacement was read from, which are not walked.
+    pub pattern_files: Vec<PathBuf>,
+    pub names: bool,
+    pub contents: bool,
+    pub verbose: bool,
+    pub content: ContentOptions,
+}
 
 pub fn walk(
-    globs: Vec<String>,
-    max_depth: usize,
     search_string: String,
     replacer_string: Option<String>,
-    replace_filenames: bool,
-    replace_contents: bool,
-    b_dry: bool,
-    b_bin: bool,
-    b_verbose: bool,
+    options: WalkOptions,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let mut b_names = replace_filenames;
-    let mut b_contents = replace_contents;
+    let mut b_names = options.names;
+    let mut b_contents = options.contents;
+    let b_verbose = options.verbose;
+    let b_bin = options.content.binary || options.content.bin_regex || options.content.bin_text;
 
     if !b_names && !b_contents {
-        b_names = true;
+        // in binary modes the search is a signature, a byte regex or text
+        // inside the file, not a name pattern
+        b_names = !b_bin;
         b_contents = true;
     }
 
-    let mut b_replace = !b_dry;
+    let mut content_options = options.content;
+    let mut b_replace = !content_options.dry;
 
     let replacer_string: &str = match &replacer_string {
         Some(s) => s,
@@ -42,10 +62,54 @@ pub fn walk(
         }
     };
 
+    let b_dry = !b_replace;
+    content_options.dry = b_dry;
+
+    // fail before touching any file if the signatures don't parse
+    if b_bin {
+        let invalid = parse_patch(&search_string, replacer_string, &content_options)
+            .err()
+            .or_else(|| {
+                let expect = content_options.expect.as_deref()?;
+                BinaryPattern::parse(expect, "", false).err()
+            });
+        if let Some(err) = invalid {
+            println!("{}", err);
+            std::process::exit(1);
+        }
+    }
+
     let base_dir = ".";
 
+    let mut globs = options.globs;
+    if let Some(backup) = &content_options.backup {
+        globs.extend(backup.ignore_globs(Path::new(base_dir)));
+    }
+
+    // the signature files of --search-file and --replace-file
+    for path in &options.pattern_files {
+        globs.extend(exclude_path_glob(path, Path::new(base_dir)));
+    }
+
+    if let Some(format) = content_options.export {
+        globs.push(format!("!*.{}", format.extension()));
+    }
+
+    if let Some(out_dir) = &mut content_options.out_dir {
+        globs.extend(exclude_path_glob(&out_dir.dir, Path::new(base_dir)));
+        if b_names {
+            let rename = Regex::new(&search_string).unwrap_or_else(|err| {
+                println!("Invalid search regex for file names: {}", err);
+                std::process::exit(1);
+            });
+            out_dir.rename = Some((rename, replacer_string.to_string()));
+        }
+    }
+
     let walker = GlobWalkerBuilder::from_patterns(base_dir, &globs)
-        .max_depth(max_depth)
+        .max_depth(options.max_depth)
+        .ignore_files(!options.no_ignore)
+        .skip_hidden(!options.hidden)
         .follow_links(true)
         .build()
         .unwrap()
@@ -57,7 +121,12 @@ pub fn walk(
     println!("Contents: {:?}", b_contents);
     println!("File names: {:?}", b_names);
     println!("Search regex: {}", search_string.clone());
-    println!("Replace regex: {}", replacer_string.clone());
+    println!("Replace regex: {}", replacer_string);
+
+    // files that must not be left as they are, like an --expect-count mismatch
+    let failed = AtomicBool::new(false);
+    // files text mode left alone because they look binary
+    let skipped_binary = AtomicUsize::new(0);
 
     walker.into_iter().par_bridge().for_each(|source_path| {
         if b_verbose {
@@ -68,54 +137,68 @@ pub fn walk(
             || _source_path == ".."
             || _source_path == "./"
             || _source_path == "../"
-            || _source_path == ""
+            || _source_path.is_empty()
             || _source_path == " "
         {
             println!("skipping: {:?}", source_path.path());
             return;
         }
 
-        let mut b_contents = b_contents;
-
-        let metadata: Metadata;
-        let file_size;
+        let b_contents = b_contents && source_path.path().is_file();
 
-        if source_path.path().is_file() {
-            let _reader = File::open(source_path.path()).unwrap();
-            metadata = _reader.metadata().unwrap();
-            file_size = metadata.len();
-        } else {
-            b_contents = false;
-        }
-        //let mut reader = BufReader::new(_reader);
+        let mut wrote_output = false;
 
         if b_contents {
             let content_info = do_contents(
                 source_path.path(),
                 search_string.as_ref(),
-                &replacer_string,
-                b_dry,
-                b_bin,
+                replacer_string,
+                &content_options,
             );
             match content_info {
                 Ok(replacement_infos) => {
+                    wrote_output = true;
                     for replacement_info in replacement_infos {
                         println!("{:?}", replacement_info.path);
+                        if let Some(err) = &replacement_info.preserve_times_error {
+                            println!("    could not preserve file times: {}", err);
+                        }
+                        if let Some(exported) = &replacement_info.exported {
+                            println!("    patch written to {:?}", exported);
+                        }
                         for replacement in replacement_info.replacements {
-                            if (!b_bin || b_verbose) { 
+                            if !b_bin
+                                || b_verbose
+                                || content_options.bin_regex
+                                || content_options.bin_text
+                                || replacement.hexdump.is_some()
+                                || !replacement.mismatches.is_empty()
+                            {
                                 println!(
-                                    "    {} at {}:{} = {:?} -> {:?}",
-                                    if !replacement_info.did_change {
-                                        "<dry>"
-                                    } else {
-                                        ""
-                                    },
-                                    replacement.start,
-                                    replacement.end,
-                                    replacement.original,
-                                    replacement.new
+                                    "{}",
+                                    describe_replacement(
+                                        &replacement,
+                                        replacement_info.did_change,
+                                        b_bin
+                                    )
                                 );
-                            }  
+                                if !replacement.mismatches.is_empty() {
+                                    println!(
+                                        "      differs from the signature at {}",
+                                        replacement
+                                            .mismatches
+                                            .iter()
+                                            .map(|offset| format!("{:#x}", offset))
+                                            .collect::<Vec<_>>()
+                                            .join(", ")
+                                    );
+                                }
+                                if let Some(hexdump) = &replacement.hexdump {
+                                    for line in hexdump.lines() {
+                                        println!("      {}", line);
+                                    }
+                                }
+                            }
                         }
                     }
                 }
@@ -130,13 +213,44 @@ pub fn walk(
                             println!("Empty file: {:?}", e);
                         }
                     }
+                    Some(DoContentError::BinaryFile) => {
+                        if content_options.binary_files == BinaryFiles::Error {
+                            println!("{:?}\n    {}", source_path.path(), e);
+                            failed.store(true, Ordering::Relaxed);
+                        } else {
+                            skipped_binary.fetch_add(1, Ordering::Relaxed);
+                            if b_verbose {
+                                println!("Skipping binary file: {:?}", source_path.path());
+                            }
+                        }
+                    }
+                    Some(DoContentError::NotElf) => {
+                        if b_verbose {
+                            println!("Not an ELF file: {:?}", source_path.path());
+                        }
+                    }
                     Some(DoContentError::ReadDecodingError(_path)) => {
                         if b_verbose {
                             println!("Read decoding error: {:?}", e);
                         }
                     }
-                    Some(DoContentError::HexDecodeError(_path, _)) => {
-                        println!("Hex decode error: {:?}", e);
+                    Some(
+                        DoContentError::UnexpectedMatchCount(..)
+                        | DoContentError::OverlappingLengthMismatch(_)
+                        | DoContentError::NoMatchAt(_)
+                        | DoContentError::ExpectMismatch(_)
+                        | DoContentError::ExportError(..)
+                        | DoContentError::UnknownSection(_)
+                        | DoContentError::UnknownSymbol(_)
+                        | DoContentError::SymbolNotInFile(_)
+                        | DoContentError::InvalidImage { .. }
+                        | DoContentError::ImageLengthChange(_),
+                    ) => {
+                        println!("{:?}\n    {}", source_path.path(), e);
+                        failed.store(true, Ordering::Relaxed);
+                    }
+                    Some(DoContentError::SignatureSyntax { .. }) => {
+                        println!("{}", e);
                         std::process::exit(1);
                     }
                     _ => {
@@ -146,26 +260,35 @@ pub fn walk(
             }
         }
 
+        if let Some(out_dir) = &content_options.out_dir {
+            let path = source_path.path();
+            if !wrote_output
+                && !b_dry
+                && path.is_file()
+                && (out_dir.copy_unmatched || out_dir.is_renamed(path))
+            {
+                if let Err(err) = copy_to(path, &out_dir.path_for(path)) {
+                    println!("Copy error: {:?}", err);
+                }
+            }
+        }
+
         if b_names {
+            // names are only changed in the output tree when there is one
             let names_info = do_names(
                 source_path.path(),
                 search_string.as_ref(),
-                &replacer_string,
-                b_dry,
+                replacer_string,
+                b_dry || content_options.out_dir.is_some(),
             );
             match names_info {
                 Ok(replacement_infos) => {
-                    for replacement_info in replacement_infos {
-                        println!(
-                            "{}, {:?} -> {:?}",
-                            if !replacement_info.did_change {
-                                "<dry>"
-                            } else {
-                                ""
-                            },
-                            replacement_info.old_name,
-                            replacement_info.new_name
-                        );
+                    for mut replacement_info in replacement_infos {
+                        if content_options.out_dir.is_some() {
+                            replacement_info.did_change = !b_dry;
+                        }
+                        println!("{:?}", replacement_info.path);
+                        println!("{}", describe_name_change(&replacement_info));
                     }
                 }
                 Err(err) => {
@@ -174,5 +297,74 @@ pub fn walk(
             }
         }
     });
+
+    let skipped_binary = skipped_binary.into_inner();
+    if skipped_binary > 0 {
+        println!(
+            "Skipped binary files: {} (--binary-files=text searches them)",
+            skipped_binary
+        );
+    }
+
+    if failed.load(Ordering::Relaxed) {
+        std::process::exit(1);
+    }
     Ok(())
 }
+
+/// The line listing a content replacement under its file, binary offsets are hex.
+pub fn describe_replacement(
+    replacement: &ContentReplacementInfo,
+    did_change: bool,
+    b_bin: bool,
+) -> String {
+    let dry = if did_change { "" } else { "<dry>" };
+    if b_bin {
+        format!(
+            "    {} at {:#x} ({} bytes) = {:?} -> {:?}",
+            dry, replacement.start, replacement.length, replacement.original, replacement.new
+        )
+    } else {
+        format!(
+            "    {} at {}:{} = {:?} -> {:?}",
+            dry, replacement.start, replacement.end, replacement.original, replacement.new
+        )
+    }
+}
+
+/// The line listing a name change under its path.
+pub fn describe_name_change(info: &NameReplacementInfo) -> String {
+    format!(
+        "    {} name = {:?} -> {:?}",
+        if info.did_change { "" } else { "<dry>" },
+        info.old_name,
+        info.new_name
+    )
+}
+
+/// Escapes glob metacharacters so `s` only matches itself.
+pub fn escape_glob(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '{' | '}' | '\\' | '!') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Glob pattern that keeps the walk away from `path`, a file or a directory,
+/// if it lies inside `base_dir`.
+pub fn exclude_path_glob(path: &Path, base_dir: &Path) -> Option<String> {
+    let absolute = |path: &Path| {
+        fs::canonicalize(path)
+            .or_else(|_| std::env::current_dir().map(|cwd| cwd.join(path)))
+            .unwrap_or_else(|_| path.to_path_buf())
+    };
+    let path = absolute(path);
+    let base_dir = absolute(base_dir);
+    let relative = path.strip_prefix(base_dir).ok()?;
+    let relative = relative.to_string_lossy().replace('\\', "/");
+    Some(format!("!/{}", escape_glob(&relative)))
+}
@@ -1,8 +1,14 @@
 use glob_walk::*;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use super::*;
-use std::fs::{create_dir_all, File};
+use crate::replace::backup::*;
+use crate::replace::contents::*;
+use crate::replace::out_dir::*;
+use crate::replace::patch_format::PatchFormat;
+use crate::replace::sniff::BinaryFiles;
+use regex::Regex;
+use std::fs::{self, create_dir_all, File};
 use tempfile::TempDir;
 
 fn touch(dir: &TempDir, names: &[&str]) {
@@ -86,7 +92,439 @@ fn test_1() {
         .build()
         .unwrap();
 
-    //equate_to_expected(glob, expected, dir_path);
+    equate_to_expected(glob, expected, dir_path);
+}
+
+/// Runs `f` inside `dir`, `walk` always starts at the working directory.
+fn in_dir<T>(dir: &Path, f: impl FnOnce() -> T) -> T {
+    static CWD: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    let _lock = CWD.lock().unwrap_or_else(|err| err.into_inner());
+    let previous = std::env::current_dir().unwrap();
+    std::env::set_current_dir(dir).unwrap();
+    let result = f();
+    std::env::set_current_dir(previous).unwrap();
+    result
+}
+
+#[test]
+fn test_missing_replacer_is_a_dry_run() {
+    let dir = TempDir::new().expect("Failed to create temporary folder");
+    let path = dir.path().join("world.txt");
+    std::fs::write(&path, "hello world").unwrap();
+
+    in_dir(dir.path(), || {
+        let options = WalkOptions {
+            globs: vec!["*".to_string()],
+            max_depth: 1,
+            ..Default::default()
+        };
+        walk("world".to_string(), None, options).unwrap()
+    });
+
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "hello world");
+}
+
+#[test]
+fn test_replacement_descriptions() {
+    let replacement = ContentReplacementInfo {
+        start: 16,
+        end: 18,
+        length: 2,
+        new: "00 00".to_string(),
+        original: "DE AD".to_string(),
+        hexdump: None,
+        mismatches: vec![],
+    };
+    assert_eq!(
+        describe_replacement(&replacement, false, true),
+        "    <dry> at 0x10 (2 bytes) = \"DE AD\" -> \"00 00\""
+    );
+    assert_eq!(
+        describe_replacement(&replacement, true, false),
+        "     at 16:18 = \"DE AD\" -> \"00 00\""
+    );
+
+    let name_change = crate::replace::names::NameReplacementInfo {
+        did_change: false,
+        path: PathBuf::from("./old.txt"),
+        old_name: "old.txt".to_string(),
+        new_name: "new.txt".to_string(),
+    };
+    assert_eq!(
+        describe_name_change(&name_change),
+        "    <dry> name = \"old.txt\" -> \"new.txt\""
+    );
+}
+
+fn write_file(dir: &TempDir, name: &str, contents: &[u8]) -> PathBuf {
+    let path = dir.path().join(normalize_path_sep(name));
+    fs::write(&path, contents).expect("Failed to write a test file");
+    path
+}
+
+#[test]
+fn test_replacement_follows_crlf_line_endings() {
+    let dir = TempDir::new().expect("Failed to create temporary folder");
+    let path = write_file(&dir, "crlf.txt", b"first\r\nsecond\r\nthird\r\n");
+
+    do_contents(
+        &path,
+        "(?m)^second$",
+        "two\nlines",
+        &ContentOptions::default(),
+    )
+    .unwrap();
+
+    assert_eq!(
+        fs::read(&path).unwrap(),
+        b"first\r\ntwo\r\nlines\r\nthird\r\n"
+    );
+}
+
+#[test]
+fn test_replacement_follows_lf_line_endings() {
+    let dir = TempDir::new().expect("Failed to create temporary folder");
+    let path = write_file(&dir, "lf.txt", b"first\nsecond\nthird\n");
+
+    do_contents(
+        &path,
+        "(?m)^second$",
+        "two\r\nlines",
+        &ContentOptions::default(),
+    )
+    .unwrap();
+
+    assert_eq!(fs::read(&path).unwrap(), b"first\ntwo\nlines\nthird\n");
+}
+
+#[test]
+fn test_detect_line_ending() {
+    assert_eq!(LineEnding::detect("a\r\nb\r\nc\n"), LineEnding::CrLf);
+    assert_eq!(LineEnding::detect("a\nb\r\nc\n"), LineEnding::Lf);
+    assert_eq!(LineEnding::detect("no line breaks"), LineEnding::Lf);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_write_atomic_keeps_permissions_and_symlinks() {
+    use crate::replace::atomic::write_atomic;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    let dir = TempDir::new().expect("Failed to create temporary folder");
+    let path = write_file(&dir, "script.sh", b"echo old\n");
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o750)).unwrap();
+    let link = dir.path().join("link.sh");
+    symlink(&path, &link).unwrap();
+
+    write_atomic(&link, b"echo new\n").unwrap();
+
+    assert!(fs::symlink_metadata(&link)
+        .unwrap()
+        .file_type()
+        .is_symlink());
+    assert_eq!(fs::read(&path).unwrap(), b"echo new\n");
+    assert_eq!(
+        fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+        0o750
+    );
+}
+
+#[test]
+fn test_backup_only_changed_files() {
+    let dir = TempDir::new().expect("Failed to create temporary folder");
+    let changed = write_file(&dir, "changed.txt", b"hello world");
+    let unchanged = write_file(&dir, "unchanged.txt", b"goodbye world");
+    let options = ContentOptions {
+        backup: Some(Backup {
+            suffix: DEFAULT_BACKUP_SUFFIX.to_string(),
+            dir: None,
+        }),
+        ..Default::default()
+    };
+
+    do_contents(&changed, "hello", "hi", &options).unwrap();
+    assert!(do_contents(&unchanged, "hello", "hi", &options).is_err());
+
+    assert_eq!(fs::read(&changed).unwrap(), b"hi world");
+    assert_eq!(
+        fs::read(dir.path().join("changed.txt.reno-bak")).unwrap(),
+        b"hello world"
+    );
+    assert!(!dir.path().join("unchanged.txt.reno-bak").exists());
+}
+
+#[test]
+fn test_backups_are_not_walked() {
+    let dir = TempDir::new().expect("Failed to create temporary folder");
+    let dir_path = dir.path();
+    create_dir_all(dir_path.join("backups/src")).expect("");
+    create_dir_all(dir_path.join("src")).expect("");
+
+    touch(
+        &dir,
+        &[
+            "a.txt",
+            "a.txt.reno-bak",
+            "src[/]b.txt",
+            "backups[/]src[/]b.txt",
+        ][..],
+    );
+
+    let backup = Backup {
+        suffix: DEFAULT_BACKUP_SUFFIX.to_string(),
+        dir: Some(dir_path.join("backups")),
+    };
+    assert_eq!(
+        backup.path_for(Path::new("./src/b.txt")),
+        dir_path.join("backups/src/b.txt.reno-bak")
+    );
+
+    let mut patterns = vec!["**/*.txt*".to_string()];
+    patterns.extend(backup.ignore_globs(dir_path));
+    let glob = GlobWalkerBuilder::from_patterns(dir_path, &patterns)
+        .build()
+        .unwrap();
+
+    let expected = ["a.txt", "src[/]b.txt"]
+        .iter()
+        .map(normalize_path_sep)
+        .collect();
+    equate_to_expected(glob, expected, dir_path);
+}
+
+#[test]
+fn test_out_dir_leaves_source_untouched() {
+    let dir = TempDir::new().expect("Failed to create temporary folder");
+    create_dir_all(dir.path().join("template")).expect("");
+    let source = write_file(&dir, "template[/]client_NAME.rs", b"struct NAMEClient;");
+    let out_dir = OutDir {
+        dir: dir.path().join("out"),
+        copy_unmatched: false,
+        rename: Some((Regex::new("NAME").unwrap(), "Acme".to_string())),
+    };
+
+    let relative = Path::new("./template/client_NAME.rs");
+    assert_eq!(
+        out_dir.path_for(relative),
+        dir.path().join("out/template/client_Acme.rs")
+    );
+    assert!(out_dir.is_renamed(relative));
+    assert!(!out_dir.is_renamed(Path::new("./template/lib.rs")));
+
+    let target = out_dir.path_for(&source);
+    let options = ContentOptions {
+        out_dir: Some(out_dir),
+        ..Default::default()
+    };
+    do_contents(&source, "NAME", "Acme", &options).unwrap();
+
+    assert_eq!(fs::read(&source).unwrap(), b"struct NAMEClient;");
+    assert_eq!(fs::read(&target).unwrap(), b"struct AcmeClient;");
+}
+
+#[test]
+fn test_preserve_times() {
+    let dir = TempDir::new().expect("Failed to create temporary folder");
+    let path = write_file(&dir, "old.txt", b"old contents");
+    let old_time = filetime::FileTime::from_unix_time(1_000_000_000, 0);
+    filetime::set_file_times(&path, old_time, old_time).unwrap();
+
+    let options = ContentOptions {
+        preserve_times: true,
+        ..Default::default()
+    };
+    let infos = do_contents(&path, "old", "new", &options).unwrap();
+
+    assert_eq!(infos[0].preserve_times_error, None);
+    assert_eq!(fs::read(&path).unwrap(), b"new contents");
+    let metadata = fs::metadata(&path).unwrap();
+    assert_eq!(
+        filetime::FileTime::from_last_modification_time(&metadata),
+        old_time
+    );
+}
+
+#[test]
+fn test_binary_match_count_limits() {
+    let dir = TempDir::new().expect("Failed to create temporary folder");
+    let path = write_file(&dir, "firmware.bin", b"\xAA\x01\xAA\x02\xAA\x03");
+
+    let expect_two = ContentOptions {
+        binary: true,
+        expect_count: Some(2),
+        ..Default::default()
+    };
+    let err = do_contents(&path, "AA ??", "BB ??", &expect_two).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<DoContentError>(),
+        Some(DoContentError::UnexpectedMatchCount(2, 3))
+    ));
+    assert_eq!(fs::read(&path).unwrap(), b"\xAA\x01\xAA\x02\xAA\x03");
+
+    let first_two = ContentOptions {
+        binary: true,
+        max_count: Some(2),
+        expect_count: Some(3),
+        ..Default::default()
+    };
+    do_contents(&path, "AA ??", "BB ??", &first_two).unwrap();
+    assert_eq!(fs::read(&path).unwrap(), b"\xBB\x01\xBB\x02\xAA\x03");
+}
+
+#[test]
+fn test_binary_export() {
+    let dir = TempDir::new().expect("Failed to create temporary folder");
+    let original = b"\x00\xDE\xAD\xBE\xEF\x00\xDE\xAD";
+    let path = write_file(&dir, "rom.bin", original);
+
+    for format in [PatchFormat::Ips, PatchFormat::Bps] {
+        let options = ContentOptions {
+            binary: true,
+            export: Some(format),
+            ..Default::default()
+        };
+        let infos = do_contents(&path, "DE AD", "12 34 56", &options).unwrap();
+        let exported = infos[0].exported.clone().unwrap();
+        assert!(!infos[0].did_change);
+        assert_eq!(fs::read(&path).unwrap(), original);
+
+        let mut expected = path.clone().into_os_string();
+        expected.push(format!(".{}", format.extension()));
+        assert_eq!(exported, PathBuf::from(expected));
+
+        let patch = fs::read(&exported).unwrap();
+        assert_eq!(
+            replace::patch_format::apply(format, original, &patch).unwrap(),
+            b"\x00\x12\x34\x56\xBE\xEF\x00\x12\x34\x56"
+        );
+    }
+}
+
+#[test]
+fn test_binary_export_overlapping() {
+    let dir = TempDir::new().expect("Failed to create temporary folder");
+    let original = b"\x00\xAA\xAA\xAA\x00";
+    let path = write_file(&dir, "rom.bin", original);
+
+    for format in [PatchFormat::Ips, PatchFormat::Bps] {
+        let options = ContentOptions {
+            binary: true,
+            overlapping: true,
+            export: Some(format),
+            ..Default::default()
+        };
+        let infos = do_contents(&path, "AA AA", "BB CC", &options).unwrap();
+        assert_eq!(infos[0].replacements.len(), 2);
+
+        // the earlier match wins, the later one only writes its last byte
+        let patch = fs::read(infos[0].exported.as_ref().unwrap()).unwrap();
+        assert_eq!(
+            replace::patch_format::apply(format, original, &patch).unwrap(),
+            b"\x00\xBB\xCC\xCC\x00"
+        );
+    }
+}
+
+#[test]
+fn test_binary_intel_hex() {
+    let dir = TempDir::new().expect("Failed to create temporary folder");
+    let path = write_file(
+        &dir,
+        "firmware.hex",
+        b":020000040800F2\n:0400000001020304F2\n:04000400deadbeefc0\n:00000001FF\n",
+    );
+
+    let options = ContentOptions {
+        binary: true,
+        at: Some(0x0800_0004),
+        ..Default::default()
+    };
+    let infos = do_contents(&path, "DE AD", "00 00", &options).unwrap();
+    assert_eq!(infos[0].replacements[0].start, 0x0800_0004);
+    assert_eq!(
+        fs::read_to_string(&path).unwrap(),
+        ":020000040800F2\n:0400000001020304F2\n:040004000000beef4b\n:00000001FF\n"
+    );
+
+    let longer = ContentOptions {
+        binary: true,
+        ..Default::default()
+    };
+    let err = do_contents(&path, "BE EF", "BE EF 00", &longer).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<DoContentError>(),
+        Some(DoContentError::ImageLengthChange(0x0800_0006))
+    ));
+}
+
+#[test]
+fn test_text_mode_skips_binary_files() {
+    let dir = TempDir::new().expect("Failed to create temporary folder");
+    let path = write_file(&dir, "lib.o", b"\x7FELF\x02\x01\x01\x00name\x00");
+
+    let err = do_contents(&path, "name", "new", &ContentOptions::default()).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<DoContentError>(),
+        Some(DoContentError::BinaryFile)
+    ));
+    assert_eq!(fs::read(&path).unwrap(), b"\x7FELF\x02\x01\x01\x00name\x00");
+
+    let as_text = ContentOptions {
+        binary_files: BinaryFiles::Text,
+        ..Default::default()
+    };
+    do_contents(&path, "name", "nom", &as_text).unwrap();
+    assert_eq!(fs::read(&path).unwrap(), b"\x7FELF\x02\x01\x01\x00nom\x00");
+}
+
+#[test]
+fn test_pattern_files_are_not_walked() {
+    let dir = TempDir::new().expect("Failed to create temporary folder");
+    let image = write_file(&dir, "image.bin", b"\x01\xDE\xAD\xBE\xEF\x02");
+    let signature = write_file(&dir, "sig.bin", b"\xDE\xAD\xBE\xEF");
+
+    in_dir(dir.path(), || {
+        let options = WalkOptions {
+            globs: vec!["*".to_string()],
+            max_depth: 1,
+            pattern_files: vec![PathBuf::from("sig.bin")],
+            contents: true,
+            content: ContentOptions {
+                binary: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        walk(
+            "DE AD BE EF".to_string(),
+            Some("00 00 00 00".to_string()),
+            options,
+        )
+        .unwrap()
+    });
+
+    assert_eq!(fs::read(&image).unwrap(), b"\x01\x00\x00\x00\x00\x02");
+    assert_eq!(fs::read(&signature).unwrap(), b"\xDE\xAD\xBE\xEF");
+}
+
+#[test]
+fn test_binary_modes_leave_names_alone() {
+    let dir = TempDir::new().expect("Failed to create temporary folder");
+    let path = write_file(&dir, "Old.bin", b"\x01Old\x02");
+
+    in_dir(dir.path(), || {
+        let options = WalkOptions {
+            globs: vec!["*".to_string()],
+            max_depth: 1,
+            content: ContentOptions {
+                bin_text: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        walk("Old".to_string(), Some("New".to_string()), options).unwrap()
+    });
 
-    ()
+    assert_eq!(fs::read(&path).unwrap(), b"\x01New\x02");
+    assert!(!dir.path().join("New.bin").exists());
 }